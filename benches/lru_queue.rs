@@ -0,0 +1,108 @@
+// Times LruQueue against the Vec backed queue it replaced, on the cache's
+// pattern of touching hot entries, removing some by key and evicting the
+// oldest. Run with cargo bench.
+#[path = "../src/lru_queue.rs"]
+#[allow(dead_code)]
+mod lru_queue;
+
+use lru_queue::LruQueue;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+// The previous implementation, with linear searches and remove(0)
+struct VecQueue<T: Eq + Clone> {
+    queue: Vec<T>,
+}
+
+impl<T: Eq + Clone> VecQueue<T> {
+    fn new() -> Self {
+        Self { queue: Vec::new() }
+    }
+
+    fn add_lru(self: &mut VecQueue<T>, new_element: &T) {
+        if let Some(position) = self.queue.iter().position(|elem| elem == new_element) {
+            let elem = self.queue.remove(position);
+            self.queue.push(elem);
+        } else {
+            self.queue.push(new_element.clone());
+        }
+    }
+
+    fn evict_lru(self: &mut VecQueue<T>) -> Option<T> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        Some(self.queue.remove(0))
+    }
+
+    fn evict_lru_by_value(self: &mut VecQueue<T>, value: &T) -> Option<T> {
+        let position = self.queue.iter().position(|x| x == value)?;
+        Some(self.queue.remove(position))
+    }
+}
+
+// The operations the cache uses, over both queues
+trait Queue {
+    fn add_lru(&mut self, key: &u64);
+    fn evict_lru(&mut self) -> Option<u64>;
+    fn evict_lru_by_value(&mut self, key: &u64) -> Option<u64>;
+}
+
+impl Queue for LruQueue<u64> {
+    fn add_lru(&mut self, key: &u64) {
+        LruQueue::add_lru(self, key)
+    }
+    fn evict_lru(&mut self) -> Option<u64> {
+        LruQueue::evict_lru(self)
+    }
+    fn evict_lru_by_value(&mut self, key: &u64) -> Option<u64> {
+        LruQueue::evict_lru_by_value(self, key)
+    }
+}
+
+impl Queue for VecQueue<u64> {
+    fn add_lru(&mut self, key: &u64) {
+        VecQueue::add_lru(self, key)
+    }
+    fn evict_lru(&mut self) -> Option<u64> {
+        VecQueue::evict_lru(self)
+    }
+    fn evict_lru_by_value(&mut self, key: &u64) -> Option<u64> {
+        VecQueue::evict_lru_by_value(self, key)
+    }
+}
+
+const OPERATIONS: usize = 100_000;
+const SIZES: [usize; 3] = [10, 1_000, 10_000];
+
+// Fill to size, then keep it there while touching, removing and evicting,
+// adding a new entry each round
+fn workload(queue: &mut impl Queue, size: usize) -> Duration {
+    let start = Instant::now();
+    for key in 0..size as u64 {
+        queue.add_lru(&key);
+    }
+    for (i, next) in (size as u64..).take(OPERATIONS).enumerate() {
+        // the newest entry, which the old queue finds last
+        queue.add_lru(&(next - 1));
+        if i % 4 == 0 {
+            black_box(queue.evict_lru_by_value(&(next - 1)));
+            queue.add_lru(&next);
+        } else {
+            queue.add_lru(&next);
+            black_box(queue.evict_lru());
+        }
+    }
+    start.elapsed()
+}
+
+fn main() {
+    for size in SIZES {
+        let linked = workload(&mut LruQueue::new(), size);
+        let vec = workload(&mut VecQueue::new(), size);
+        println!(
+            "{:>6} entries, {} operations: linked {:>10.2?}, vec {:>10.2?}",
+            size, OPERATIONS, linked, vec
+        );
+    }
+}
@@ -1,12 +1,18 @@
-use crate::lru_queue::LruQueue;
-use crate::request::Request;
+use crate::cache_key::CacheKey;
+use crate::headers::Headers;
+use crate::eviction::EvictionPolicy;
+use crate::method::Method;
+use crate::url::Url;
 use std::collections::HashMap;
 use std::error::Error;
 use std::time::Instant;
 
 #[derive(Clone)]
 pub struct CacheRecord {
-    pub request: Request,
+    // Host and request target of the request that stored it, as in the log
+    // lines. Requests in origin and absolute-form share the entry.
+    pub host: String,
+    pub target: String,
     pub response: Vec<u8>,
     pub time_now: Instant,
     pub expiry_secs: Option<u32>,
@@ -16,14 +22,16 @@ pub struct CacheRecord {
 impl CacheRecord {
     // Assume all response have Date, following specs
     pub fn new(
-        request: Request,
+        host: String,
+        target: String,
         response: Vec<u8>,
         time_now: Instant,
         expiry_secs: Option<u32>,
         date: Option<String>,
     ) -> Self {
         Self {
-            request,
+            host,
+            target,
             response,
             time_now,
             expiry_secs,
@@ -32,22 +40,32 @@ impl CacheRecord {
     }
 }
 
-pub struct Cache {
-    lru: LruQueue<String>,
-    cache: HashMap<String, CacheRecord>,
+// Stored responses within a byte budget, the eviction policy choosing what
+// goes when room is needed
+pub struct Cache<P: EvictionPolicy<CacheKey>> {
+    policy: P,
+    cache: HashMap<CacheKey, CacheRecord>,
+    // keys of the stored variants of each resource
+    variants: HashMap<CacheKey, Vec<CacheKey>>,
+    // total bytes of the stored responses, kept within capacity
+    size: usize,
+    capacity: usize,
+    max_object_size: usize,
 }
 
-impl Cache {
-    const CACHE_MAX: usize = 10;
-
-    pub fn new() -> Self {
+impl<P: EvictionPolicy<CacheKey>> Cache<P> {
+    pub fn new(policy: P, capacity: usize, max_object_size: usize) -> Self {
         Self {
-            lru: LruQueue::new(),
+            policy,
             cache: HashMap::new(),
+            variants: HashMap::new(),
+            size: 0,
+            capacity,
+            max_object_size,
         }
     }
 
-    fn check_time_out(self: &Cache, time_now: &Instant, expiry: Option<u32>) -> bool {
+    fn check_time_out(self: &Cache<P>, time_now: &Instant, expiry: Option<u32>) -> bool {
         // println!("Expiry: {:?}", expiry);
         let Some(expiry_secs) = expiry else {
             return false;
@@ -63,63 +81,120 @@ impl Cache {
         false
     }
 
-    // Returns (entry, is_expired) from the cache given the request, none if the cache doesn't exist
-    pub fn get(self: &mut Cache, request: &String) -> Option<(CacheRecord, bool)> {
-        let entry_ref = self.cache.get(request)?;
+    // Stored variants of the resource a request with these headers selects,
+    // each going by its own Vary, from the least to the most recently stored
+    pub fn matching_variants(self: &Cache<P>, resource: &CacheKey, headers: &Headers) -> Vec<CacheKey> {
+        self.variants
+            .get(resource)
+            .map(|keys| keys.iter().filter(|key| key.matches(headers)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // The variant of the resource to answer a request with these headers from
+    pub fn find_variant(self: &Cache<P>, resource: &CacheKey, headers: &Headers) -> Option<CacheKey> {
+        self.matching_variants(resource, headers).pop()
+    }
+
+    // Returns (entry, is_expired) from the cache given the key, none if the cache doesn't exist
+    pub fn get(self: &mut Cache<P>, key: &CacheKey) -> Option<(CacheRecord, bool)> {
+        let entry_ref = self.cache.get(key)?;
         if self.check_time_out(&entry_ref.time_now, entry_ref.expiry_secs) {
             return Some((entry_ref.clone(), true));
         }
 
-        // If in cache, move to end of lru
-        self.lru.add_lru(request);
+        // Let the policy know the entry is still in use
+        self.policy.touch(key);
         Some((entry_ref.clone(), false))
     }
 
     // Adds
     pub fn add_cache(
-        self: &mut Cache,
-        request_data: String,
-        request: Request,
+        self: &mut Cache<P>,
+        key: CacheKey,
+        host: String,
+        target: String,
         response_data: Vec<u8>,
         expiry: Option<u32>,
         date: Option<String>,
     ) -> Result<(), Box<dyn Error>> {
-        if self.cache.len() == Self::CACHE_MAX {
+        if !self.has_room(&key, response_data.len()) {
             return Err("cache is full".into());
         }
 
-        self.lru.add_lru(&request_data);
+        // Each variant is its own entry to the policy. The newest variant
+        // goes last, so it wins when several match.
+        if self.cache.contains_key(&key) {
+            self.policy.touch(&key);
+        } else {
+            self.policy.insert(&key);
+        }
+        let keys = self.variants.entry(key.resource()).or_default();
+        keys.retain(|other| *other != key);
+        keys.push(key.clone());
         let time_now = Instant::now();
-        self.cache.insert(
-            request_data,
-            CacheRecord::new(request, response_data, time_now, expiry, date),
+        self.size += response_data.len();
+        let replaced = self.cache.insert(
+            key,
+            CacheRecord::new(host, target, response_data, time_now, expiry, date),
         );
-        
+        if let Some(replaced) = replaced {
+            self.size -= replaced.response.len();
+        }
+
         Ok(())
     }
 
-    pub fn is_full(self: &Cache) -> bool {
-        self.cache.len() == Self::CACHE_MAX
+    pub fn contains(self: &Cache<P>, key: &CacheKey) -> bool {
+        self.cache.contains_key(key)
     }
 
-    pub fn remove_lru_cache(self: &mut Cache) -> Result<CacheRecord, Box<dyn Error>> {
-        if self.cache.len() == Self::CACHE_MAX {
-            // try to remove lru
-            let evicted_key = self.lru.evict_lru().ok_or("lru empty when evicting")?;
-            let evicted = self.cache.remove(&evicted_key)
-                .ok_or("evicted lru key doesn't exist in cache")?;
-            return Ok(evicted);
-        }
+    // Whether a response of this many bytes may be stored at all
+    pub fn admits(self: &Cache<P>, size: usize) -> bool {
+        size <= self.max_object_size && size <= self.capacity
+    }
 
-        Err("cache is not full".into())
+    // Whether a response of this many bytes fits under key without evicting
+    // anything. Overwriting an entry frees its bytes.
+    pub fn has_room(self: &Cache<P>, key: &CacheKey, size: usize) -> bool {
+        let replaced = self.cache.get(key).map_or(0, |record| record.response.len());
+        self.size - replaced + size <= self.capacity
     }
 
-    pub fn remove_cache(self: &mut Cache, request: &String) -> Result<CacheRecord, Box<dyn Error>> {
-        self.lru
-            .evict_lru_by_value(request)
-            .ok_or("no lru value exists when removing request")?;
-        let record = self.cache.remove(request)
-            .ok_or("evicted lru key doesn't exist in cache")?;
+    // Evict the entry the policy picks, giving it back
+    pub fn evict(self: &mut Cache<P>) -> Result<CacheRecord, Box<dyn Error>> {
+        let evicted_key = self.policy.evict().ok_or("nothing to evict")?;
+        self.remove_entry(&evicted_key)
+    }
+
+    // Remove every variant stored for url, giving them back
+    pub fn invalidate(self: &mut Cache<P>, url: &Url) -> Result<Vec<CacheRecord>, Box<dyn Error>> {
+        let resource = CacheKey {
+            method: Method::Get,
+            url: url.clone(),
+            vary: Vec::new(),
+        };
+        let keys = self.variants.get(&resource).cloned().unwrap_or_default();
+        keys.iter().map(|key| self.remove_cache(key)).collect()
+    }
+
+    pub fn remove_cache(self: &mut Cache<P>, key: &CacheKey) -> Result<CacheRecord, Box<dyn Error>> {
+        let record = self.remove_entry(key)?;
+        self.policy.remove(key);
+        Ok(record)
+    }
+
+    // Remove an entry the policy already forgot
+    fn remove_entry(self: &mut Cache<P>, key: &CacheKey) -> Result<CacheRecord, Box<dyn Error>> {
+        let record = self.cache.remove(key)
+            .ok_or("evicted key doesn't exist in cache")?;
+        self.size -= record.response.len();
+        let resource = key.resource();
+        if let Some(keys) = self.variants.get_mut(&resource) {
+            keys.retain(|other| other != key);
+            if keys.is_empty() {
+                self.variants.remove(&resource);
+            }
+        }
         Ok(record)
     }
 }
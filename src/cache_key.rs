@@ -0,0 +1,69 @@
+use crate::headers::Headers;
+use crate::method::Method;
+use crate::request::Request;
+use crate::url::Url;
+
+// What a stored response is found by: the method and target of the request it
+// answered, plus the request headers named by its Vary header
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub method: Method,
+    pub url: Url,
+    // lowercase names of the Vary headers, with the request's values (None
+    // when the request didn't have the header)
+    pub vary: Vec<(String, Option<String>)>,
+}
+
+impl CacheKey {
+    // Key of the resource a request is for, before any Vary headers are
+    // added. HEAD is answered from what a GET stored.
+    pub fn new(request: &Request, url: &Url) -> Self {
+        let method = match request.method {
+            Method::Head => Method::Get,
+            ref method => method.clone(),
+        };
+        Self {
+            method,
+            url: url.clone(),
+            vary: Vec::new(),
+        }
+    }
+
+    // Key of the variant of this resource selected by the named headers
+    pub fn with_vary(self: CacheKey, names: &[String], headers: &Headers) -> Self {
+        let vary = names
+            .iter()
+            .map(|name| (name.to_lowercase(), Self::header_value(headers, name)))
+            .collect();
+        Self { vary, ..self }
+    }
+
+    // Whether a request with these headers selects this variant, its Vary
+    // headers having the same values (RFC 9111 section 4.1)
+    pub fn matches(self: &CacheKey, headers: &Headers) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| Self::header_value(headers, name) == *value)
+    }
+
+    // Value of a header as compared between requests, with the whitespace
+    // around list items normalized
+    fn header_value(headers: &Headers, name: &str) -> Option<String> {
+        headers.get_combined(name).map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        })
+    }
+
+    // Key of the resource, whichever variant this is
+    pub fn resource(self: &CacheKey) -> Self {
+        Self {
+            method: self.method.clone(),
+            url: self.url.clone(),
+            vary: Vec::new(),
+        }
+    }
+}
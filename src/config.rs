@@ -0,0 +1,294 @@
+use crate::eviction::Eviction;
+use crate::http_parser::ObsFold;
+use std::error::Error;
+use std::time::Duration;
+
+// Server core serving the client connections
+#[derive(Clone, Copy, PartialEq)]
+pub enum IoMode {
+    // Blocking accept loop handing connections to a reactor, which reads
+    // request headers while workers serve the rest
+    Threads,
+    // Non-blocking epoll reactor reading request headers, workers serve the rest
+    Epoll,
+}
+
+// Header telling the origin who the request was forwarded for
+#[derive(Clone, Copy, PartialEq)]
+pub enum ForwardedHeader {
+    // X-Forwarded-For, the client address appended to any earlier ones
+    XForwardedFor,
+    // X-Forwarded-Proto, the scheme the client used
+    XForwardedProto,
+    // Forwarded (RFC 7239), the standard form of both
+    Forwarded,
+}
+
+// Startup options for the proxy, parsed from the command line
+pub struct Config {
+    pub port: u16,
+    pub does_cache: bool,
+    pub io_mode: IoMode,
+    // Number of workers serving connections concurrently
+    pub threads: usize,
+    // How long a client connection may wait for its next request
+    pub keep_alive_timeout: Duration,
+    // Requests served on one client connection before it is closed
+    pub max_requests: usize,
+    // Idle upstream connections kept per host and port
+    pub upstream_max_idle: usize,
+    // How long an upstream connection may sit idle before it isn't reused
+    pub upstream_idle_timeout: Duration,
+    // Largest request body forwarded upstream, in bytes
+    pub max_body_size: usize,
+    // Total bytes of responses the cache holds
+    pub cache_size: usize,
+    // Largest response the cache stores, in bytes
+    pub cache_object_size: usize,
+    // Which entry the cache gives up when it needs room
+    pub eviction: Eviction,
+    // Destination ports a CONNECT tunnel may be opened to
+    pub connect_ports: Vec<u16>,
+    // How long a tunnel may go without traffic before it is closed
+    pub tunnel_idle_timeout: Duration,
+    // Protocols a request may switch the connection to with Upgrade, lowercase
+    pub upgrade_protocols: Vec<String>,
+    // Whether a request asking only for other protocols gets 403, rather than
+    // being served as if it hadn't asked (RFC 9110 section 7.8)
+    pub strict_upgrade: bool,
+    // How long an upgraded connection may go without traffic before it is closed
+    pub upgrade_idle_timeout: Duration,
+    // How long opening an upstream connection may take
+    pub connect_timeout: Duration,
+    // How long the origin may take to start its response once the request is sent
+    pub first_byte_timeout: Duration,
+    // Longest gap allowed between reads or writes of a body, either side
+    pub body_timeout: Duration,
+    // How long a client may take to send a whole request header
+    pub header_timeout: Duration,
+    // How long to wait for the origin's 100 Continue before sending a request
+    // body anyway
+    pub expect_timeout: Duration,
+    // Headers added to forwarded requests on top of Via
+    pub forwarded_headers: Vec<ForwardedHeader>,
+    // Whether headers with obsolete line folding are refused or unfolded
+    pub obs_fold: ObsFold,
+}
+
+impl Config {
+    const DEFAULT_THREADS: usize = 8;
+    const DEFAULT_KEEP_ALIVE_SECS: u64 = 5;
+    const DEFAULT_MAX_REQUESTS: usize = 100;
+    const DEFAULT_UPSTREAM_MAX_IDLE: usize = 8;
+    const DEFAULT_UPSTREAM_IDLE_SECS: u64 = 30;
+    const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024; // 10MiB
+    const DEFAULT_CACHE_SIZE: usize = 10 * 1024 * 1024; // 10MiB
+    const DEFAULT_CACHE_OBJECT_SIZE: usize = 100 * 1024; // 100KiB
+    const DEFAULT_CONNECT_PORTS: [u16; 1] = [443];
+    const DEFAULT_TUNNEL_IDLE_SECS: u64 = 60;
+    const DEFAULT_UPGRADE_PROTOCOLS: [&'static str; 1] = ["websocket"];
+    const DEFAULT_UPGRADE_IDLE_SECS: u64 = 60;
+    const DEFAULT_CONNECT_SECS: u64 = 10;
+    const DEFAULT_FIRST_BYTE_SECS: u64 = 30;
+    const DEFAULT_BODY_SECS: u64 = 30;
+    const DEFAULT_HEADER_SECS: u64 = 10;
+    const DEFAULT_EXPECT_SECS: u64 = 1;
+    const LIST_SEPARATOR: char = ',';
+
+    // Reads the options, where args[0] is the program name
+    pub fn from_args(args: &[String]) -> Result<Self, Box<dyn Error>> {
+        let mut config = Config {
+            port: 0,
+            does_cache: false,
+            io_mode: IoMode::Threads,
+            threads: Self::DEFAULT_THREADS,
+            keep_alive_timeout: Duration::from_secs(Self::DEFAULT_KEEP_ALIVE_SECS),
+            max_requests: Self::DEFAULT_MAX_REQUESTS,
+            upstream_max_idle: Self::DEFAULT_UPSTREAM_MAX_IDLE,
+            upstream_idle_timeout: Duration::from_secs(Self::DEFAULT_UPSTREAM_IDLE_SECS),
+            max_body_size: Self::DEFAULT_MAX_BODY_SIZE,
+            cache_size: Self::DEFAULT_CACHE_SIZE,
+            cache_object_size: Self::DEFAULT_CACHE_OBJECT_SIZE,
+            eviction: Eviction::Lru,
+            connect_ports: Self::DEFAULT_CONNECT_PORTS.to_vec(),
+            tunnel_idle_timeout: Duration::from_secs(Self::DEFAULT_TUNNEL_IDLE_SECS),
+            upgrade_protocols: Self::DEFAULT_UPGRADE_PROTOCOLS.map(String::from).to_vec(),
+            strict_upgrade: false,
+            upgrade_idle_timeout: Duration::from_secs(Self::DEFAULT_UPGRADE_IDLE_SECS),
+            connect_timeout: Duration::from_secs(Self::DEFAULT_CONNECT_SECS),
+            first_byte_timeout: Duration::from_secs(Self::DEFAULT_FIRST_BYTE_SECS),
+            body_timeout: Duration::from_secs(Self::DEFAULT_BODY_SECS),
+            header_timeout: Duration::from_secs(Self::DEFAULT_HEADER_SECS),
+            expect_timeout: Duration::from_secs(Self::DEFAULT_EXPECT_SECS),
+            forwarded_headers: Vec::new(),
+            obs_fold: ObsFold::Reject,
+        };
+
+        let mut i = 1;
+        while i < args.len() {
+            match args[i].as_str() {
+                "-p" => {
+                    config.port = Self::value(args, i, "-p need a port")?.parse::<u16>()?;
+                    i += 2;
+                }
+                "-c" => {
+                    config.does_cache = true;
+                    i += 1;
+                }
+                "-t" => {
+                    config.threads = Self::value(args, i, "-t need a thread count")?.parse::<usize>()?;
+                    if config.threads == 0 {
+                        return Err("-t need at least one thread".into());
+                    }
+                    i += 2;
+                }
+                "--io" => {
+                    config.io_mode = match Self::value(args, i, "--io need a mode")? {
+                        "threads" => IoMode::Threads,
+                        "epoll" => IoMode::Epoll,
+                        mode => return Err(format!("unknown io mode {}", mode).into()),
+                    };
+                    i += 2;
+                }
+                "--keep-alive-timeout" => {
+                    config.keep_alive_timeout =
+                        Self::timeout(args, i, "--keep-alive-timeout need seconds")?;
+                    i += 2;
+                }
+                "--max-requests" => {
+                    config.max_requests =
+                        Self::value(args, i, "--max-requests need a count")?.parse::<usize>()?;
+                    i += 2;
+                }
+                "--upstream-max-idle" => {
+                    config.upstream_max_idle =
+                        Self::value(args, i, "--upstream-max-idle need a count")?.parse::<usize>()?;
+                    i += 2;
+                }
+                "--upstream-idle-timeout" => {
+                    config.upstream_idle_timeout = Duration::from_secs(
+                        Self::value(args, i, "--upstream-idle-timeout need seconds")?.parse::<u64>()?,
+                    );
+                    i += 2;
+                }
+                "-b" => {
+                    config.max_body_size =
+                        Self::value(args, i, "-b need a body size")?.parse::<usize>()?;
+                    i += 2;
+                }
+                "--cache-size" => {
+                    config.cache_size =
+                        Self::value(args, i, "--cache-size need a size in bytes")?.parse::<usize>()?;
+                    i += 2;
+                }
+                "--cache-object-size" => {
+                    config.cache_object_size =
+                        Self::value(args, i, "--cache-object-size need a size in bytes")?.parse::<usize>()?;
+                    i += 2;
+                }
+                "--eviction" => {
+                    config.eviction = match Self::value(args, i, "--eviction need a policy")? {
+                        "lru" => Eviction::Lru,
+                        "lfu" => Eviction::Lfu,
+                        "sieve" => Eviction::Sieve,
+                        "tinylfu" => Eviction::TinyLfu,
+                        policy => return Err(format!("unknown eviction policy {}", policy).into()),
+                    };
+                    i += 2;
+                }
+                "--connect-ports" => {
+                    config.connect_ports = Self::value(args, i, "--connect-ports need a port list")?
+                        .split(Self::LIST_SEPARATOR)
+                        .map(|port| port.trim().parse::<u16>())
+                        .collect::<Result<Vec<u16>, _>>()?;
+                    i += 2;
+                }
+                "--tunnel-idle-timeout" => {
+                    config.tunnel_idle_timeout =
+                        Self::timeout(args, i, "--tunnel-idle-timeout need seconds")?;
+                    i += 2;
+                }
+                "--upgrade-protocols" => {
+                    config.upgrade_protocols = Self::value(args, i, "--upgrade-protocols need a protocol list")?
+                        .split(Self::LIST_SEPARATOR)
+                        .map(|protocol| protocol.trim().to_lowercase())
+                        .filter(|protocol| !protocol.is_empty())
+                        .collect();
+                    i += 2;
+                }
+                "--strict-upgrade" => {
+                    config.strict_upgrade = true;
+                    i += 1;
+                }
+                "--upgrade-idle-timeout" => {
+                    config.upgrade_idle_timeout =
+                        Self::timeout(args, i, "--upgrade-idle-timeout need seconds")?;
+                    i += 2;
+                }
+                "--connect-timeout" => {
+                    config.connect_timeout = Self::timeout(args, i, "--connect-timeout need seconds")?;
+                    i += 2;
+                }
+                "--first-byte-timeout" => {
+                    config.first_byte_timeout =
+                        Self::timeout(args, i, "--first-byte-timeout need seconds")?;
+                    i += 2;
+                }
+                "--body-timeout" => {
+                    config.body_timeout = Self::timeout(args, i, "--body-timeout need seconds")?;
+                    i += 2;
+                }
+                "--header-timeout" => {
+                    config.header_timeout = Self::timeout(args, i, "--header-timeout need seconds")?;
+                    i += 2;
+                }
+                "--expect-timeout" => {
+                    config.expect_timeout = Self::timeout(args, i, "--expect-timeout need seconds")?;
+                    i += 2;
+                }
+                "--forwarded-headers" => {
+                    config.forwarded_headers = Self::value(args, i, "--forwarded-headers need a header list")?
+                        .split(Self::LIST_SEPARATOR)
+                        .map(|header| match header.trim().to_lowercase().as_str() {
+                            "x-forwarded-for" => Ok(ForwardedHeader::XForwardedFor),
+                            "x-forwarded-proto" => Ok(ForwardedHeader::XForwardedProto),
+                            "forwarded" => Ok(ForwardedHeader::Forwarded),
+                            header => Err(format!("unknown forwarded header {}", header)),
+                        })
+                        .collect::<Result<Vec<ForwardedHeader>, _>>()?;
+                    i += 2;
+                }
+                "--obs-fold" => {
+                    config.obs_fold = match Self::value(args, i, "--obs-fold need a mode")? {
+                        "reject" => ObsFold::Reject,
+                        "unfold" => ObsFold::Unfold,
+                        mode => return Err(format!("unknown obs-fold mode {}", mode).into()),
+                    };
+                    i += 2;
+                }
+                _ => {
+                    return Err(format!("unknown argument {}", args[i]).into());
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    // The value following the flag at position i
+    fn value<'a>(args: &'a [String], i: usize, missing: &str) -> Result<&'a str, Box<dyn Error>> {
+        args.get(i + 1)
+            .map(String::as_str)
+            .ok_or_else(|| missing.into())
+    }
+
+    // The seconds following the flag at position i, as a socket timeout. Zero
+    // is refused since sockets take it to mean no timeout at all.
+    fn timeout(args: &[String], i: usize, missing: &str) -> Result<Duration, Box<dyn Error>> {
+        let secs = Self::value(args, i, missing)?.parse::<u64>()?;
+        if secs == 0 {
+            return Err(format!("{} need at least one second", args[i]).into());
+        }
+        Ok(Duration::from_secs(secs))
+    }
+}
@@ -0,0 +1,99 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+// Minimal bindings to the Linux epoll API, std has none and the crate has no
+// dependencies. libc itself is always linked in by std.
+mod ffi {
+    // The kernel packs this struct on x86_64 only
+    #[repr(C)]
+    #[cfg_attr(target_arch = "x86_64", repr(packed))]
+    #[derive(Clone, Copy)]
+    pub struct EpollEvent {
+        pub events: u32,
+        pub data: u64,
+    }
+
+    pub const EPOLL_CLOEXEC: i32 = 0o2000000;
+    pub const EPOLL_CTL_ADD: i32 = 1;
+    pub const EPOLL_CTL_DEL: i32 = 2;
+
+    extern "C" {
+        pub fn epoll_create1(flags: i32) -> i32;
+        pub fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut EpollEvent) -> i32;
+        pub fn epoll_wait(epfd: i32, events: *mut EpollEvent, maxevents: i32, timeout: i32) -> i32;
+    }
+}
+
+pub const EPOLLIN: u32 = 0x001;
+pub const EPOLLRDHUP: u32 = 0x2000;
+
+// Level-triggered epoll instance
+pub struct Epoll {
+    fd: OwnedFd,
+    events: Vec<ffi::EpollEvent>,
+}
+
+impl Epoll {
+    const MAX_EVENTS: usize = 1024;
+
+    pub fn new() -> io::Result<Self> {
+        let fd = Self::check(unsafe { ffi::epoll_create1(ffi::EPOLL_CLOEXEC) })?;
+        Ok(Self {
+            // Safe as the fd was just created and is owned by nobody else
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            events: vec![ffi::EpollEvent { events: 0, data: 0 }; Self::MAX_EVENTS],
+        })
+    }
+
+    // Watch fd for the given events, reported with token
+    pub fn add(self: &Epoll, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        let mut event = ffi::EpollEvent {
+            events,
+            data: token,
+        };
+        Self::check(unsafe {
+            ffi::epoll_ctl(self.fd.as_raw_fd(), ffi::EPOLL_CTL_ADD, fd, &mut event)
+        })?;
+        Ok(())
+    }
+
+    pub fn delete(self: &Epoll, fd: RawFd) -> io::Result<()> {
+        // Kernels before 2.6.9 require a non-null event even for delete
+        let mut event = ffi::EpollEvent { events: 0, data: 0 };
+        Self::check(unsafe {
+            ffi::epoll_ctl(self.fd.as_raw_fd(), ffi::EPOLL_CTL_DEL, fd, &mut event)
+        })?;
+        Ok(())
+    }
+
+    // Wait until at least one fd is ready or the timeout passes, returning the
+    // tokens of the ready ones. Errors and hang ups are reported as ready too.
+    pub fn wait(self: &mut Epoll, timeout: Option<Duration>) -> io::Result<Vec<u64>> {
+        let timeout = timeout.map_or(-1, |timeout| timeout.as_millis().min(i32::MAX as u128) as i32);
+        let ready = loop {
+            let ready = unsafe {
+                ffi::epoll_wait(
+                    self.fd.as_raw_fd(),
+                    self.events.as_mut_ptr(),
+                    self.events.len() as i32,
+                    timeout,
+                )
+            };
+            match Self::check(ready) {
+                Ok(ready) => break ready as usize,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        Ok(self.events[..ready].iter().map(|event| event.data).collect())
+    }
+
+    fn check(result: i32) -> io::Result<i32> {
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(result)
+    }
+}
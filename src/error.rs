@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// What went wrong while serving a request, and so what the client is told.
+// Parsing errors are reported as if the message came from the client, those
+// that happen while talking to the origin are moved over with upstream().
+#[derive(Debug)]
+pub enum ProxyError {
+    // 400, the request isn't valid HTTP
+    BadRequest(String),
+    // 431, the request header is over the size limit
+    HeaderTooLarge(String),
+    // 408, the client took too long over its request header
+    RequestTimeout(String),
+    // 403, the request is for something this proxy won't do (a tunnel to a
+    // port not allowed, ...)
+    Forbidden(String),
+    // 413, the request body is over the size limit
+    PayloadTooLarge(String),
+    // 417, the request expects something this proxy can't meet
+    ExpectationFailed(String),
+    // 502, the origin couldn't be reached or answered with invalid HTTP
+    BadGateway(String),
+    // 504, the origin took too long
+    GatewayTimeout(String),
+    // 500, the proxy itself failed
+    Internal(String),
+    // The response was already under way, so the client can only be cut off
+    Interrupted(String),
+    // A connection failed or closed. Reading from the origin is moved over by
+    // upstream(), otherwise it is the client's and there is no one to tell.
+    Io(io::Error),
+}
+
+impl ProxyError {
+    // Status code and reason phrase to answer the client with, if any
+    pub fn status(self: &ProxyError) -> Option<(u16, &'static str)> {
+        match self {
+            ProxyError::BadRequest(_) => Some((400, "Bad Request")),
+            ProxyError::HeaderTooLarge(_) => Some((431, "Request Header Fields Too Large")),
+            ProxyError::RequestTimeout(_) => Some((408, "Request Timeout")),
+            ProxyError::Forbidden(_) => Some((403, "Forbidden")),
+            ProxyError::PayloadTooLarge(_) => Some((413, "Payload Too Large")),
+            ProxyError::ExpectationFailed(_) => Some((417, "Expectation Failed")),
+            ProxyError::BadGateway(_) => Some((502, "Bad Gateway")),
+            ProxyError::GatewayTimeout(_) => Some((504, "Gateway Timeout")),
+            ProxyError::Internal(_) => Some((500, "Internal Server Error")),
+            ProxyError::Interrupted(_) | ProxyError::Io(_) => None,
+        }
+    }
+
+    // The same error, for something that went wrong talking to the origin
+    pub fn upstream(self: ProxyError) -> ProxyError {
+        match self {
+            ProxyError::BadRequest(message) | ProxyError::HeaderTooLarge(message) => {
+                ProxyError::BadGateway(message)
+            }
+            ProxyError::RequestTimeout(message) => ProxyError::GatewayTimeout(message),
+            ProxyError::Io(err) if Self::is_timeout_kind(err.kind()) => {
+                ProxyError::GatewayTimeout("origin did not answer in time".to_string())
+            }
+            ProxyError::Io(err) => ProxyError::BadGateway(err.to_string()),
+            err => err,
+        }
+    }
+
+    // The same error, once part of the response has been sent to the client
+    pub fn interrupted(self: ProxyError) -> ProxyError {
+        match self {
+            ProxyError::Io(_) | ProxyError::Interrupted(_) => self,
+            err => ProxyError::Interrupted(err.to_string()),
+        }
+    }
+
+    // Whether a read or connect ran out of time. Blocking sockets report their
+    // timeouts as WouldBlock.
+    pub fn is_timeout(self: &ProxyError) -> bool {
+        match self {
+            ProxyError::RequestTimeout(_) | ProxyError::GatewayTimeout(_) => true,
+            ProxyError::Io(err) => Self::is_timeout_kind(err.kind()),
+            _ => false,
+        }
+    }
+
+    fn is_timeout_kind(kind: io::ErrorKind) -> bool {
+        matches!(kind, io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::BadRequest(message) => write!(f, "bad request: {}", message),
+            ProxyError::HeaderTooLarge(message) => write!(f, "header too large: {}", message),
+            ProxyError::RequestTimeout(message) => write!(f, "request timeout: {}", message),
+            ProxyError::Forbidden(message) => write!(f, "forbidden: {}", message),
+            ProxyError::PayloadTooLarge(message) => write!(f, "payload too large: {}", message),
+            ProxyError::ExpectationFailed(message) => write!(f, "expectation failed: {}", message),
+            ProxyError::BadGateway(message) => write!(f, "bad gateway: {}", message),
+            ProxyError::GatewayTimeout(message) => write!(f, "gateway timeout: {}", message),
+            ProxyError::Internal(message) => write!(f, "internal error: {}", message),
+            ProxyError::Interrupted(message) => write!(f, "response interrupted: {}", message),
+            ProxyError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ProxyError {}
+
+impl From<io::Error> for ProxyError {
+    fn from(err: io::Error) -> Self {
+        ProxyError::Io(err)
+    }
+}
+
+// Errors of the modules still on Box<dyn Error> (cache, upstream pool, ...),
+// where anything but an I/O failure is the proxy's own
+impl From<Box<dyn Error>> for ProxyError {
+    fn from(err: Box<dyn Error>) -> Self {
+        match err.downcast::<io::Error>() {
+            Ok(err) => ProxyError::Io(*err),
+            Err(err) => ProxyError::Internal(err.to_string()),
+        }
+    }
+}
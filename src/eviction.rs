@@ -0,0 +1,142 @@
+use crate::lfu::Lfu;
+use crate::lru_queue::LruQueue;
+use crate::sieve::Sieve;
+use crate::tiny_lfu::WTinyLfu;
+use std::hash::Hash;
+
+// Which eviction policy the cache runs
+#[derive(Clone, Copy, PartialEq)]
+pub enum Eviction {
+    // Least recently used
+    Lru,
+    // Least frequently used, least recently used among equals
+    Lfu,
+    // SIEVE, a FIFO queue with a hand sparing visited entries
+    Sieve,
+    // W-TinyLFU, an LRU window in front of a frequency filtered main area
+    TinyLfu,
+}
+
+// Decides which cache entry goes when the cache needs room. The cache tells
+// it about every entry stored, read and removed.
+pub trait EvictionPolicy<T> {
+    // A new entry was stored
+    fn insert(&mut self, key: &T);
+    // A stored entry was read or overwritten
+    fn touch(&mut self, key: &T);
+    // The cache removed an entry itself (stale, invalidated, ...)
+    fn remove(&mut self, key: &T);
+    // Pick the entry to evict next and forget it
+    fn evict(&mut self) -> Option<T>;
+}
+
+// The policy chosen at startup
+pub fn new_policy<T>(eviction: Eviction) -> Box<dyn EvictionPolicy<T> + Send>
+where
+    T: Eq + Clone + Hash + Send + 'static,
+{
+    match eviction {
+        Eviction::Lru => Box::new(LruQueue::new()),
+        Eviction::Lfu => Box::new(Lfu::new()),
+        Eviction::Sieve => Box::new(Sieve::new()),
+        Eviction::TinyLfu => Box::new(WTinyLfu::new()),
+    }
+}
+
+impl<T, P> EvictionPolicy<T> for Box<P>
+where
+    P: EvictionPolicy<T> + ?Sized,
+{
+    fn insert(&mut self, key: &T) {
+        (**self).insert(key)
+    }
+
+    fn touch(&mut self, key: &T) {
+        (**self).touch(key)
+    }
+
+    fn remove(&mut self, key: &T) {
+        (**self).remove(key)
+    }
+
+    fn evict(&mut self) -> Option<T> {
+        (**self).evict()
+    }
+}
+
+impl<T> EvictionPolicy<T> for LruQueue<T>
+where
+    T: Eq + Clone + Hash,
+{
+    fn insert(&mut self, key: &T) {
+        self.add_lru(key);
+    }
+
+    fn touch(&mut self, key: &T) {
+        self.add_lru(key);
+    }
+
+    fn remove(&mut self, key: &T) {
+        self.evict_lru_by_value(key);
+    }
+
+    fn evict(&mut self) -> Option<T> {
+        self.evict_lru()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Drain the policy, giving back its evictions in order
+    pub fn evict_all(policy: &mut impl EvictionPolicy<u32>) -> Vec<u32> {
+        std::iter::from_fn(|| policy.evict()).collect()
+    }
+
+    // Invariants every policy keeps, whatever order it evicts in: each stored
+    // key is evicted exactly once, removed keys never are, and an empty
+    // policy has nothing to evict
+    pub fn check_invariants(mut policy: impl EvictionPolicy<u32>) {
+        assert_eq!(policy.evict(), None);
+
+        for key in 0..100 {
+            policy.insert(&key);
+        }
+        for key in (0..100).step_by(3) {
+            policy.touch(&key);
+            policy.touch(&key);
+        }
+        for key in (0..100).step_by(5) {
+            policy.remove(&key);
+        }
+        // Removing twice, or a key never stored, changes nothing
+        policy.remove(&0);
+        policy.remove(&1000);
+
+        let mut evicted = evict_all(&mut policy);
+        evicted.sort();
+        let expected = (0..100).filter(|key| key % 5 != 0).collect::<Vec<u32>>();
+        assert_eq!(evicted, expected);
+        assert_eq!(policy.evict(), None);
+
+        // Still usable once emptied
+        policy.insert(&7);
+        assert_eq!(evict_all(&mut policy), vec![7]);
+    }
+
+    #[test]
+    fn lru_invariants() {
+        check_invariants(LruQueue::new());
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut lru = LruQueue::new();
+        for key in 1..=3 {
+            lru.insert(&key);
+        }
+        lru.touch(&1);
+        assert_eq!(evict_all(&mut lru), vec![2, 3, 1]);
+    }
+}
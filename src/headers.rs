@@ -1,12 +1,49 @@
-use crate::http_parser::HttpParser;
+use crate::error::ProxyError;
+use crate::http_parser;
 use std::error::Error;
 
-pub const IF_MODIFIED_SINCE_HEADER: &'static str = "If-Modified-Since";
-pub const CONTENT_LENGTH_HEADER: &'static str = "content-length";
-pub const CACHE_CONTROL_HEADER: &'static str = "cache-control";
-pub const DATE_HEADER: &'static str = "date";
-pub const DATE_HEADER_DEFAULT: &'static str = "Wed, 21 May 2025 01:01:56 GMT";
-const CACHE_DISALLOWED_ENTRIES: [&'static str; 6] = [
+pub const IF_MODIFIED_SINCE_HEADER: &str = "If-Modified-Since";
+pub const HOST_HEADER: &str = "Host";
+pub const CONTENT_LENGTH_HEADER: &str = "content-length";
+pub const TRANSFER_ENCODING_HEADER: &str = "transfer-encoding";
+pub const CHUNKED_ENCODING: &str = "chunked";
+pub const CONNECTION_HEADER: &str = "connection";
+pub const CLOSE_TOKEN: &str = "close";
+pub const KEEP_ALIVE_TOKEN: &str = "keep-alive";
+pub const CACHE_CONTROL_HEADER: &str = "cache-control";
+pub const DATE_HEADER: &str = "date";
+pub const DATE_HEADER_DEFAULT: &str = "Wed, 21 May 2025 01:01:56 GMT";
+pub const CONNECTION_FIELD: &str = "Connection";
+pub const CONTENT_LENGTH_FIELD: &str = "Content-Length";
+pub const VIA_HEADER: &str = "Via";
+pub const X_FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+pub const X_FORWARDED_PROTO_HEADER: &str = "X-Forwarded-Proto";
+pub const FORWARDED_HEADER: &str = "Forwarded";
+pub const UPGRADE_HEADER: &str = "Upgrade";
+pub const UPGRADE_TOKEN: &str = "upgrade";
+pub const EXPECT_HEADER: &str = "expect";
+pub const CONTINUE_EXPECTATION: &str = "100-continue";
+pub const VARY_HEADER: &str = "vary";
+// Vary value for a response that depends on more than request headers
+pub const VARY_ANY: &str = "*";
+pub const LOCATION_HEADER: &str = "location";
+pub const CONTENT_LOCATION_HEADER: &str = "content-location";
+// Headers that only concern the connection they arrive on (RFC 9110 section
+// 7.6.1), plus the proxy credentials meant for this proxy alone.
+// Transfer-Encoding is hop-by-hop too, but bodies are forwarded with their
+// framing as is, so it has to go along with them.
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    CONNECTION_HEADER,
+    "proxy-connection",
+    KEEP_ALIVE_TOKEN,
+    "te",
+    "upgrade",
+    "proxy-authorization",
+    "proxy-authenticate",
+];
+// Framing headers that are never dropped, even when listed in Connection
+const FRAMING_HEADERS: [&str; 2] = [CONTENT_LENGTH_HEADER, TRANSFER_ENCODING_HEADER];
+const CACHE_DISALLOWED_ENTRIES: [&str; 6] = [
     "private",
     "no-store",
     "no-cache",
@@ -14,21 +51,203 @@ const CACHE_DISALLOWED_ENTRIES: [&'static str; 6] = [
     "must-revalidate",
     "proxy-revalidate",
 ];
-const MAX_AGE_ENTRY: &'static str = "max-age=";
+const MAX_AGE_ENTRY: &str = "max-age=";
 
 // Appends the header key value pair to a header_lines that ends with the \r\n
 pub fn append_header(header_lines: String, key: &String, value: &String) -> String {
-    let stripped = header_lines[..header_lines.len() - HttpParser::CRLF_LEN].to_owned();
+    let stripped = header_lines[..header_lines.len() - http_parser::CRLF_LEN].to_owned();
     format!(
         "{}{}: {}{}{}",
         stripped,
         key,
         value,
-        HttpParser::CRLF,
-        HttpParser::CRLF
+        http_parser::CRLF,
+        http_parser::CRLF
     )
 }
 
+// Replaces the request-target in the request line of header_lines
+pub fn replace_request_target(header_lines: String, target: &str) -> String {
+    let Some((request_line, rest)) = header_lines.split_once(http_parser::CRLF) else {
+        return header_lines;
+    };
+    let parts = request_line.split(' ').collect::<Vec<&str>>();
+    let [method, _target, version] = parts[..] else {
+        return header_lines;
+    };
+    format!("{} {} {}{}{}", method, target, version, http_parser::CRLF, rest)
+}
+
+// Characters allowed in a token such as a field name, besides letters and
+// digits (RFC 9110 section 5.6.2)
+const TOKEN_SYMBOLS: &[u8] = b"!#$%&'*+-.^_`|~";
+// Optional whitespace around field values
+const OWS: [char; 2] = [' ', '\t'];
+
+// Whether s is a non-empty token
+pub fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || TOKEN_SYMBOLS.contains(&b))
+}
+
+// Split a field line into its name and value, following RFC 9112 section 5:
+// the name is a token right up to the colon, the value is trimmed of
+// optional whitespace, and may not hold CR, LF or NUL. Errors are reported
+// as the client's.
+pub fn parse_field_line(line: &str) -> Result<(String, String), ProxyError> {
+    let (name, value) = line
+        .split_once(':')
+        .ok_or_else(|| ProxyError::BadRequest(format!("header line without a colon {}", line)))?;
+    if name.ends_with(OWS) {
+        return Err(ProxyError::BadRequest(format!("whitespace before colon in header {}", line)));
+    }
+    if !is_token(name) {
+        return Err(ProxyError::BadRequest(format!("invalid header name {}", name)));
+    }
+    // A bare CR or LF would be taken as a line end further along (RFC 9110
+    // section 5.5)
+    if value.contains(['\r', '\n', '\0']) {
+        return Err(ProxyError::BadRequest(format!("invalid character in header {}", name)));
+    }
+    Ok((name.to_string(), value.trim_matches(OWS).to_string()))
+}
+
+// Name of a field line, or None for a line that isn't one
+fn field_name(line: &str) -> Option<&str> {
+    line.split_once(':').map(|(name, _)| name.trim())
+}
+
+// Values of the named header in header_lines, in order, ignoring case
+pub fn header_values(header_lines: &str, name: &str) -> Vec<String> {
+    header_lines
+        .split(http_parser::CRLF)
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(field, _)| field.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+        .collect()
+}
+
+// Keeps the start line and the field lines whose name keep accepts
+fn retain_headers(header_lines: String, keep: impl Fn(&str) -> bool) -> String {
+    header_lines
+        .split(http_parser::CRLF)
+        .enumerate()
+        .filter(|(i, line)| *i == 0 || field_name(line).is_none_or(&keep))
+        .map(|(_, line)| line)
+        .collect::<Vec<&str>>()
+        .join(http_parser::CRLF)
+}
+
+// Removes every line of the named header, ignoring case
+pub fn remove_header(header_lines: String, name: &str) -> String {
+    retain_headers(header_lines, |field| !field.eq_ignore_ascii_case(name))
+}
+
+// Removes the hop-by-hop headers, along with any header the Connection
+// header names, so they don't travel past this proxy
+pub fn strip_hop_by_hop(header_lines: String) -> String {
+    let named = header_values(&header_lines, CONNECTION_HEADER)
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_lowercase())
+        .filter(|token| !FRAMING_HEADERS.contains(&token.as_str()))
+        .collect::<Vec<String>>();
+    retain_headers(header_lines, |field| {
+        let field = field.to_lowercase();
+        !HOP_BY_HOP_HEADERS.contains(&field.as_str()) && !named.contains(&field)
+    })
+}
+
+// Whether a comma separated header value lists the token, ignoring case
+pub fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
+// Whether chunked is the final coding of a Transfer-Encoding value
+pub fn is_chunked(encoding: &str) -> bool {
+    encoding
+        .rsplit(',')
+        .next()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case(CHUNKED_ENCODING))
+}
+
+// Header fields of a message in the order they arrived, names in their
+// original case. Lookup ignores case, and a field may appear more than once
+// (Set-Cookie, or a list split over several lines).
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Add a field after the existing ones, keeping any of the same name
+    pub fn append(self: &mut Headers, name: String, value: String) {
+        self.fields.push((name, value));
+    }
+
+    // First value of the named field
+    pub fn get(self: &Headers, name: &str) -> Option<&String> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    // Every value of the named field, in order
+    pub fn get_all(self: &Headers, name: &str) -> Vec<&String> {
+        self.fields
+            .iter()
+            .filter(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    // Every value of the named field joined into one comma separated list,
+    // which means the same for list fields (RFC 9110 section 5.3)
+    pub fn get_combined(self: &Headers, name: &str) -> Option<String> {
+        let values = self.get_all(name);
+        if values.is_empty() {
+            return None;
+        }
+        Some(values.iter().map(|value| value.as_str()).collect::<Vec<&str>>().join(", "))
+    }
+
+    pub fn contains(self: &Headers, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // Content-Length as a number. Repeated lines must agree (RFC 9112 section
+    // 6.3), anything else is an error reported as the client's.
+    pub fn content_length(self: &Headers) -> Result<Option<usize>, ProxyError> {
+        let mut content_length = None;
+        for value in self.get_all(CONTENT_LENGTH_HEADER) {
+            // A single line may also carry a list of the same value
+            for item in value.split(',') {
+                // 1*DIGIT only, parse() would also take a sign
+                let item = item.trim();
+                let invalid = || ProxyError::BadRequest(format!("invalid content length {}", value));
+                if item.is_empty() || !item.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(invalid());
+                }
+                let length = item.parse::<usize>().map_err(|_| invalid())?;
+                if content_length.is_some_and(|content_length| content_length != length) {
+                    return Err(ProxyError::BadRequest("conflicting content lengths".to_string()));
+                }
+                content_length = Some(length);
+            }
+        }
+        Ok(content_length)
+    }
+}
+
 pub struct CacheControlHeader {
     words: Vec<String>,
 }
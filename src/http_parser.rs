@@ -1,67 +1,208 @@
+use crate::error::ProxyError;
 use crate::request::Request;
 use crate::response::Response;
-use std::error::Error;
-use std::io::Read;
-use std::net::TcpStream;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
 
-// Http stream parser
-pub struct HttpParser<'a> {
-    stream: &'a mut TcpStream,
+pub const CRLF: &str = "\r\n";
+pub const CRLF_BYTES: &[u8] = CRLF.as_bytes();
+pub const CRLF_LEN: usize = CRLF.len();
+// A header ends with an empty line
+pub const HEAD_END: &[u8] = b"\r\n\r\n";
+
+// Framing of a message body, following RFC 9112 section 6.3
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyLength {
+    Empty,
+    ContentLength(usize),
+    Chunked,
+    // No framing, the body ends when the connection closes (HTTP/1.0 style)
+    UntilClose,
+}
+
+// What to do with obsolete line folding, a field line starting with
+// whitespace that continues the previous one (RFC 9112 section 5.2)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObsFold {
+    // Fail the message
+    Reject,
+    // Join the line onto the previous one with a single space, for the
+    // header forwarded on and the one parsed alike
+    Unfold,
+}
+
+// Where the parser is within the body being read
+enum BodyState {
+    Done,
+    // Remaining bytes of a Content-Length body
+    Fixed(usize),
+    // Expecting a chunk-size line (or the last chunk and trailers)
+    ChunkSize,
+    // Remaining bytes of the current chunk's data
+    ChunkData(usize),
+    // Expecting the CRLF after a chunk's data
+    ChunkDataEnd,
+    // After the last chunk, reading trailer lines up to the blank line, with
+    // the bytes of trailers read so far
+    Trailers(usize),
+    // Reading until the peer closes the connection
+    UntilClose,
+}
+
+// Http stream parser. Works on blocking and non-blocking streams alike: on a
+// non-blocking one a WouldBlock error leaves the parser where it was, and the
+// same read call resumes once more bytes arrive.
+pub struct HttpParser<R: Read> {
+    stream: R,
     // buffer for the currently read but unhandled bytes
     buffer: Vec<u8>,
     // data for the entire request/response
     data: Vec<u8>,
     // header length for both request/response
     header_length: usize,
+    // whether data holds part of a header still being read
+    reading_head: bool,
+    // progress through the body after the header
+    body: BodyState,
+    // payload bytes of the body read so far, excluding chunk framing
+    body_size: usize,
+    // how long a header may take to arrive from its first byte, if limited
+    head_timeout: Option<Duration>,
+    // when the first byte of the header being read arrived
+    head_started: Option<Instant>,
+    obs_fold: ObsFold,
 }
 
-impl<'a> HttpParser<'a> {
-    pub const CRLF: &'static str = "\r\n";
-    pub const CRLF_BYTES: &'static [u8] = "\r\n".as_bytes();
-    pub const CRLF_LEN: usize = Self::CRLF.len();
+impl<R: Read> HttpParser<R> {
     const READ_BUFFER_SIZE: usize = 1024;
     const MAX_HEADER_SIZE: usize = 8192; // 8KiB
+    const CHUNK_EXTENSION_SEPARATOR: char = ';';
+
+    pub fn new(stream: R) -> Self {
+        Self::with_buffer(stream, Vec::new())
+    }
 
-    pub fn new(stream: &'a mut TcpStream) -> Self {
+    // Parser starting with bytes already read off the stream
+    pub fn with_buffer(stream: R, buffer: Vec<u8>) -> Self {
         HttpParser {
             stream,
-            buffer: Vec::new(),
+            buffer,
             data: Vec::new(),
             header_length: 0,
+            reading_head: false,
+            body: BodyState::Done,
+            body_size: 0,
+            head_timeout: None,
+            head_started: None,
+            obs_fold: ObsFold::Reject,
+        }
+    }
+
+    pub fn set_obs_fold(self: &mut HttpParser<R>, obs_fold: ObsFold) {
+        self.obs_fold = obs_fold;
+    }
+
+    // Limit the time a header may take from its first byte to its blank line.
+    // Going over fails the read with a TimedOut error.
+    pub fn set_head_timeout(self: &mut HttpParser<R>, timeout: Option<Duration>) {
+        self.head_timeout = timeout;
+    }
+
+    // When the header being read runs out of time, if it is limited
+    pub fn head_deadline(self: &HttpParser<R>) -> Option<Instant> {
+        Some(self.head_started? + self.head_timeout?)
+    }
+
+    // Whether the header being read has taken longer than the head timeout
+    pub fn is_head_overdue(self: &HttpParser<R>) -> bool {
+        self.head_deadline().is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // Start the head timeout clock once the first byte of a header is in
+    fn start_head_clock(self: &mut HttpParser<R>) {
+        if self.reading_head && self.head_started.is_none() && !self.buffer.is_empty() {
+            self.head_started = Some(Instant::now());
         }
     }
 
     // Return the header lines in utf-8
-    pub fn header_lines(self: &HttpParser<'a>) -> Result<String, Box<dyn Error>> {
-        Ok(String::from_utf8(
-            self.data[..self.header_length].to_owned(),
-        )?)
+    pub fn header_lines(self: &HttpParser<R>) -> Result<String, ProxyError> {
+        String::from_utf8(self.data[..self.header_length].to_owned())
+            .map_err(|_| ProxyError::BadRequest("header is not valid UTF-8".to_string()))
     }
 
     // Returns parser data
-    pub fn data(self: &HttpParser<'a>) -> Vec<u8> {
+    pub fn data(self: &HttpParser<R>) -> Vec<u8> {
         self.data.clone()
     }
 
+    pub fn get_ref(self: &HttpParser<R>) -> &R {
+        &self.stream
+    }
+
+    pub fn into_inner(self: HttpParser<R>) -> R {
+        self.stream
+    }
+
+    // Give back the stream and the bytes read off it, the header read so far
+    // followed by the buffer, so another parser can take the message over from
+    // its start. Only meant for a parser that has read nothing past a header.
+    pub fn into_parts(self: HttpParser<R>) -> (R, Vec<u8>) {
+        let mut unread = self.data;
+        unread.extend_from_slice(&self.buffer);
+        (self.stream, unread)
+    }
+
+    // Whether any bytes of a next message have been read, so that the peer
+    // closing now would cut a message short
+    pub fn has_partial_message(self: &HttpParser<R>) -> bool {
+        (self.reading_head && !self.data.is_empty()) || !self.buffer.is_empty()
+    }
+
+    // Whether a whole next header is already buffered, so it can be read
+    // without waiting on the stream (e.g. pipelined requests)
+    pub fn has_buffered_head(self: &HttpParser<R>) -> bool {
+        let mut start = 0;
+        while self.buffer[start..].starts_with(CRLF_BYTES) {
+            start += CRLF_LEN;
+        }
+        self.buffer[start..]
+            .windows(HEAD_END.len())
+            .any(|window| window == HEAD_END)
+    }
+
+    // Hand over the read but unparsed bytes, e.g. when switching to a tunnel
+    pub fn take_buffer(self: &mut HttpParser<R>) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
     // Read a single line ended by \r\n, return the bytes as is
-    fn read_line(self: &mut HttpParser<'a>) -> Result<Vec<u8>, Box<dyn Error>> {
+    fn read_line(self: &mut HttpParser<R>) -> Result<Vec<u8>, ProxyError> {
         loop {
+            self.start_head_clock();
+            // check for \r\n
+            if let Some(index) = self.buffer.windows(2).position(|w| w == CRLF_BYTES) {
+                let line = self.buffer[..index + CRLF_LEN].to_owned();
+                self.buffer = self.buffer[index + CRLF_LEN..].to_owned();
+                return Ok(line);
+            }
+
             // Check if pass the size limit. Throw error if it is
-            if self.buffer.len() > HttpParser::MAX_HEADER_SIZE {
-                return Err("Header line is longer than 8KiB".into());
+            if self.buffer.len() > Self::MAX_HEADER_SIZE {
+                if self.reading_head {
+                    return Err(ProxyError::HeaderTooLarge("Header line is longer than 8KiB".to_string()));
+                }
+                return Err(ProxyError::BadRequest("Line is longer than 8KiB".to_string()));
             }
 
-            // check for \r\n
-            if let Some(index) = self.buffer.windows(2).position(|w| w == Self::CRLF_BYTES) {
-                let line = self.buffer[..index + Self::CRLF_LEN].to_owned();
-                self.buffer = self.buffer[index + Self::CRLF_LEN..].to_owned();
-                return Ok(line);
+            if self.reading_head && self.is_head_overdue() {
+                return Err(ProxyError::RequestTimeout("header took too long to arrive".to_string()));
             }
 
             let mut buffer = vec![0; Self::READ_BUFFER_SIZE];
             let bytes_read = self.stream.read(&mut buffer)?;
             if bytes_read == 0 {
-                return Err("reader closed unexpectedly".into());
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "reader closed unexpectedly").into());
             }
             buffer.resize(bytes_read, 0);
             // Max can reach is 8 + 1 = 9KiB, so fine
@@ -69,73 +210,262 @@ impl<'a> HttpParser<'a> {
         }
     }
 
-    // Read a http request from the stream
-    pub fn read_request(self: &mut HttpParser<'a>) -> Result<Request, Box<dyn Error>> {
-        self.data.clear();
+    // Read header lines up to and including the blank line. Resumes a header
+    // left partially read by an earlier WouldBlock.
+    fn read_head(self: &mut HttpParser<R>, kind: &str) -> Result<String, ProxyError> {
+        if !self.reading_head {
+            self.data.clear();
+            self.reading_head = true;
+            self.head_started = None;
+        }
 
         loop {
             let line = self.read_line()?;
-            // Max is 19KiB, so should be fine
-            self.data.extend_from_slice(&line);
+            // Empty lines before a request line are ignored (RFC 9112 section 2.2),
+            // some clients send an extra CRLF after a body
+            if self.data.is_empty() && line == CRLF_BYTES {
+                continue;
+            }
+
+            if !self.data.is_empty() && (line.starts_with(b" ") || line.starts_with(b"\t")) {
+                self.unfold_line(&line, kind)?;
+            } else {
+                // Max is 19KiB, so should be fine
+                self.data.extend_from_slice(&line);
+            }
 
-            let line = String::from_utf8(line)?;
-            if line == Self::CRLF {
+            if line == CRLF_BYTES {
+                self.reading_head = false;
+                self.head_started = None;
                 self.header_length = self.data.len();
-                return Request::from_string(String::from_utf8(self.data.clone())?);
+                return String::from_utf8(self.data.clone()).map_err(|_| {
+                    ProxyError::BadRequest(format!("{} header is not valid UTF-8", kind))
+                });
             }
 
-            if self.data.len() > HttpParser::MAX_HEADER_SIZE {
-                return Err("Request header reaches above 8KiB limit".into());
+            if self.data.len() > Self::MAX_HEADER_SIZE {
+                self.reading_head = false;
+                return Err(ProxyError::HeaderTooLarge(format!("{} header reaches above 8KiB limit", kind)));
             }
         }
     }
 
-    // Read an http response from the stream, will also consume the blank line
-    pub fn read_response_header(self: &mut HttpParser<'a>) -> Result<Response, Box<dyn Error>> {
-        self.data.clear();
+    // Handle a line of obsolete line folding, joining it onto the field line
+    // before it or failing the header, as obs_fold says
+    fn unfold_line(self: &mut HttpParser<R>, line: &[u8], kind: &str) -> Result<(), ProxyError> {
+        // Whitespace right after the start line can't continue anything
+        let start_line_length = self
+            .data
+            .windows(CRLF_LEN)
+            .position(|window| window == CRLF_BYTES)
+            .map_or(0, |index| index + CRLF_LEN);
+        if self.obs_fold == ObsFold::Reject || self.data.len() <= start_line_length {
+            self.reading_head = false;
+            self.head_started = None;
+            return Err(ProxyError::BadRequest(format!("{} header has obsolete line folding", kind)));
+        }
 
-        loop {
-            let line = self.read_line()?;
-            // Max is 19KiB, so should be fine
-            self.data.extend_from_slice(&line);
+        let continuation = line
+            .iter()
+            .position(|b| *b != b' ' && *b != b'\t')
+            .unwrap_or(line.len());
+        self.data.truncate(self.data.len() - CRLF_LEN);
+        self.data.push(b' ');
+        self.data.extend_from_slice(&line[continuation..]);
+        Ok(())
+    }
 
-            let line = String::from_utf8(line)?;
-            if line == Self::CRLF {
-                self.header_length = self.data.len();
-                return Response::from_string(String::from_utf8(self.data.clone())?);
-            }
+    // Read a http request from the stream
+    pub fn read_request(self: &mut HttpParser<R>) -> Result<Request, ProxyError> {
+        let head = self.read_head("Request")?;
+        Request::from_string(head)
+    }
+
+    // Read an http response from the stream, will also consume the blank line
+    pub fn read_response_header(self: &mut HttpParser<R>) -> Result<Response, ProxyError> {
+        let head = self.read_head("Response")?;
+        Response::from_string(head)
+    }
 
-            if self.data.len() > HttpParser::MAX_HEADER_SIZE {
-                return Err("Response header reaches above 8KiB limit".into());
+    // Read headers up to the final response's, handing each interim (1xx)
+    // response before it to on_interim along with its header lines
+    pub fn read_final_response_header(
+        self: &mut HttpParser<R>,
+        mut on_interim: impl FnMut(&Response, String) -> Result<(), ProxyError>,
+    ) -> Result<Response, ProxyError> {
+        loop {
+            let response = self.read_response_header()?;
+            if !response.is_interim() {
+                return Ok(response);
             }
+            on_interim(&response, self.header_lines()?)?;
         }
     }
 
-    // Read a series of bytes
-    pub fn read_bytes(self: &mut HttpParser<'a>, max_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
-        // Read the remaining after reading the header
-        // Note, since buffer is shrunk to its exact length, no need to truncate
-        // bunch of zeros
-        if self.buffer.len() > 0 {
-            self.data.extend_from_slice(&self.buffer);
-            let result = self.buffer.to_vec();
-            self.buffer.clear();
+    // Read at most max_len bytes, taking the leftover buffer before the stream.
+    // An empty result means the peer closed the connection.
+    fn read_bytes(self: &mut HttpParser<R>, max_len: usize) -> Result<Vec<u8>, ProxyError> {
+        if !self.buffer.is_empty() {
+            let len = max_len.min(self.buffer.len());
+            let result = self.buffer[..len].to_vec();
+            self.buffer.drain(..len);
             return Ok(result);
         }
 
-        let mut buffer = vec![0; Self::READ_BUFFER_SIZE];
+        let mut buffer = vec![0; max_len.min(Self::READ_BUFFER_SIZE)];
         let bytes_read = self.stream.read(&mut buffer)?;
-        if bytes_read == 0 {
-            return Err("reader closed unexpectedly".into());
-        }
         buffer.resize(bytes_read, 0);
+        Ok(buffer)
+    }
+
+    // Read at most max_len bytes, where the peer closing the connection is an error
+    fn read_framed_bytes(self: &mut HttpParser<R>, max_len: usize) -> Result<Vec<u8>, ProxyError> {
+        let bytes = self.read_bytes(max_len)?;
+        if bytes.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "reader closed unexpectedly").into());
+        }
+        Ok(bytes)
+    }
+
+    // Parse the hex size of a chunk-size line, ignoring any chunk extensions
+    fn parse_chunk_size(line: &[u8]) -> Result<usize, ProxyError> {
+        let line = std::str::from_utf8(&line[..line.len() - CRLF_LEN])
+            .map_err(|_| ProxyError::BadRequest("chunk size line is not valid UTF-8".to_string()))?;
+        let size = line
+            .split(Self::CHUNK_EXTENSION_SEPARATOR)
+            .next()
+            .unwrap_or_default()
+            .trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ProxyError::BadRequest(format!("invalid chunk size line {}", line)));
+        }
+        usize::from_str_radix(size, 16)
+            .map_err(|_| ProxyError::BadRequest(format!("chunk size too large in {}", line)))
+    }
+
+    // Prepare to read a body with the given framing, right after the header
+    pub fn start_body(self: &mut HttpParser<R>, length: BodyLength) {
+        self.body = match length {
+            BodyLength::Empty | BodyLength::ContentLength(0) => BodyState::Done,
+            BodyLength::ContentLength(length) => BodyState::Fixed(length),
+            BodyLength::Chunked => BodyState::ChunkSize,
+            BodyLength::UntilClose => BodyState::UntilClose,
+        };
+        self.body_size = 0;
+    }
+
+    // Whether the whole body has been read, up to its framing or a clean close
+    pub fn is_body_complete(self: &HttpParser<R>) -> bool {
+        matches!(self.body, BodyState::Done)
+    }
+
+    // Payload bytes of the body read so far, excluding chunk framing
+    pub fn body_size(self: &HttpParser<R>) -> usize {
+        self.body_size
+    }
+
+    // Read the next piece of the body, returned as is (chunk framing included)
+    // so it can be forwarded. Returns None once the body is complete. Each
+    // piece is only taken off the buffer once whole, so a WouldBlock loses
+    // nothing.
+    pub fn read_body(self: &mut HttpParser<R>, max_size: usize) -> Result<Option<Vec<u8>>, ProxyError> {
+        let bytes = match self.body {
+            BodyState::Done => return Ok(None),
+            BodyState::Fixed(remaining) => {
+                let bytes = self.read_framed_bytes(remaining)?;
+                self.body_size += bytes.len();
+                self.body = match remaining - bytes.len() {
+                    0 => BodyState::Done,
+                    remaining => BodyState::Fixed(remaining),
+                };
+                bytes
+            }
+            BodyState::ChunkSize => {
+                let bytes = self.read_line()?;
+                // The last chunk is followed by the trailer section and a blank line
+                self.body = match Self::parse_chunk_size(&bytes)? {
+                    0 => BodyState::Trailers(0),
+                    size => BodyState::ChunkData(size),
+                };
+                bytes
+            }
+            BodyState::ChunkData(remaining) => {
+                let bytes = self.read_framed_bytes(remaining)?;
+                self.body_size += bytes.len();
+                self.body = match remaining - bytes.len() {
+                    0 => BodyState::ChunkDataEnd,
+                    remaining => BodyState::ChunkData(remaining),
+                };
+                bytes
+            }
+            BodyState::ChunkDataEnd => {
+                // Chunk data is always followed by a CRLF
+                let line = self.read_line()?;
+                if line != CRLF_BYTES {
+                    return Err(ProxyError::BadRequest("chunk data not terminated by CRLF".to_string()));
+                }
+                self.body = BodyState::ChunkSize;
+                line
+            }
+            BodyState::Trailers(trailer_length) => {
+                let line = self.read_line()?;
+                if line == CRLF_BYTES {
+                    self.body = BodyState::Done;
+                } else {
+                    let trailer_length = trailer_length + line.len();
+                    if trailer_length > Self::MAX_HEADER_SIZE {
+                        return Err(ProxyError::HeaderTooLarge(
+                            "Chunked trailer reaches above 8KiB limit".to_string(),
+                        ));
+                    }
+                    self.body = BodyState::Trailers(trailer_length);
+                }
+                line
+            }
+            BodyState::UntilClose => {
+                let bytes = self.read_bytes(Self::READ_BUFFER_SIZE)?;
+                if bytes.is_empty() {
+                    // EOF is the valid end of this body
+                    self.body = BodyState::Done;
+                    return Ok(None);
+                }
+                self.body_size += bytes.len();
+                bytes
+            }
+        };
 
         // No need to store if the length exceeds cache requirement.
-        // Max size reached would be 100kib + 1,024b, which is acceptable.
+        // Max size reached would be max_size + 9KiB, which is acceptable.
         if self.data.len() <= max_size {
-            self.data.extend_from_slice(&buffer);
+            self.data.extend_from_slice(&bytes);
+        }
+        Ok(Some(bytes))
+    }
+
+    // Like read_body, but with the chunk framing and trailers left out, for a
+    // peer that can't read them. data still gets the body as is.
+    pub fn read_payload(self: &mut HttpParser<R>, max_size: usize) -> Result<Option<Vec<u8>>, ProxyError> {
+        loop {
+            let body_size = self.body_size;
+            let Some(mut bytes) = self.read_body(max_size)? else {
+                return Ok(None);
+            };
+            // Payload bytes always come first in a piece, any framing after
+            bytes.truncate(self.body_size - body_size);
+            if !bytes.is_empty() {
+                return Ok(Some(bytes));
+            }
         }
-        Ok(buffer)
     }
+}
 
+// Payload of a whole chunked body, as stored in the cache
+pub fn decode_chunked(body: &[u8]) -> Result<Vec<u8>, ProxyError> {
+    let mut parser = HttpParser::new(body);
+    parser.start_body(BodyLength::Chunked);
+    let mut payload = Vec::new();
+    while let Some(bytes) = parser.read_payload(0)? {
+        payload.extend_from_slice(&bytes);
+    }
+    Ok(payload)
 }
@@ -0,0 +1,98 @@
+use crate::eviction::EvictionPolicy;
+use crate::lru_queue::LruQueue;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// Least frequently used eviction. Entries are bucketed by how often they were
+// used while cached, each bucket in least recently used order, so ties go to
+// the entry unused for longest.
+pub struct Lfu<T>
+where
+    T: Eq + Clone + Hash,
+{
+    counts: HashMap<T, usize>,
+    buckets: BTreeMap<usize, LruQueue<T>>,
+}
+
+impl<T> Lfu<T>
+where
+    T: Eq + Clone + Hash,
+{
+    pub fn new() -> Self {
+        Self {
+            counts: HashMap::new(),
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    // Move key into the bucket for count, dropping its old bucket if emptied
+    fn set_count(self: &mut Lfu<T>, key: &T, count: usize) {
+        if let Some(old) = self.counts.insert(key.clone(), count) {
+            self.take_from_bucket(key, old);
+        }
+        self.buckets.entry(count).or_insert_with(LruQueue::new).add_lru(key);
+    }
+
+    fn take_from_bucket(self: &mut Lfu<T>, key: &T, count: usize) {
+        if let Some(bucket) = self.buckets.get_mut(&count) {
+            bucket.evict_lru_by_value(key);
+            if bucket.is_empty() {
+                self.buckets.remove(&count);
+            }
+        }
+    }
+}
+
+impl<T> EvictionPolicy<T> for Lfu<T>
+where
+    T: Eq + Clone + Hash,
+{
+    fn insert(&mut self, key: &T) {
+        self.set_count(key, 1);
+    }
+
+    fn touch(&mut self, key: &T) {
+        let count = self.counts.get(key).map_or(1, |count| count + 1);
+        self.set_count(key, count);
+    }
+
+    fn remove(&mut self, key: &T) {
+        if let Some(count) = self.counts.remove(key) {
+            self.take_from_bucket(key, count);
+        }
+    }
+
+    fn evict(&mut self) -> Option<T> {
+        let mut bucket = self.buckets.first_entry()?;
+        let key = bucket.get_mut().evict_lru()?;
+        if bucket.get().is_empty() {
+            bucket.remove();
+        }
+        self.counts.remove(&key);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eviction::tests::{check_invariants, evict_all};
+
+    #[test]
+    fn lfu_invariants() {
+        check_invariants(Lfu::new());
+    }
+
+    #[test]
+    fn lfu_evicts_least_used_then_least_recent() {
+        let mut lfu = Lfu::new();
+        for key in 1..=4 {
+            lfu.insert(&key);
+        }
+        lfu.touch(&1);
+        lfu.touch(&1);
+        lfu.touch(&3);
+        // 2 and 4 were used once, 2 longer ago
+        assert_eq!(evict_all(&mut lfu), vec![2, 4, 3, 1]);
+    }
+}
@@ -1,43 +1,142 @@
-// Maybe implement a linked list with WeakRef or hacks (for performance?)
-// Lecturer allows Vec #765
+use std::collections::HashMap;
+use std::hash::Hash;
+
+// A node of the queue's doubly linked list, linked by slot index
+struct Node<T> {
+    value: T,
+    prev: usize,
+    next: usize,
+}
+
+// Least recently used order over distinct values. A doubly linked list kept
+// in a slab of nodes, indexed by a map from value to slot, so touching,
+// evicting the oldest and removing any value all take constant time.
 pub struct LruQueue<T>
 where
-    T: Eq + PartialEq + Clone,
+    T: Eq + PartialEq + Clone + Hash,
 {
-    queue: Vec<T>,
+    // slots of the list nodes, None once freed
+    nodes: Vec<Option<Node<T>>>,
+    // freed slots to reuse before growing nodes
+    free: Vec<usize>,
+    index: HashMap<T, usize>,
+    // least recently used end
+    head: usize,
+    // most recently used end
+    tail: usize,
 }
 
 impl<T> LruQueue<T>
 where
-    T: Eq + PartialEq + Clone,
+    T: Eq + PartialEq + Clone + Hash,
 {
+    // Link index of an empty end
+    const NIL: usize = usize::MAX;
+
     pub fn new() -> Self {
-        Self { queue: Vec::new() }
+        Self {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            index: HashMap::new(),
+            head: Self::NIL,
+            tail: Self::NIL,
+        }
     }
 
+    // Mark an element as the most recently used, adding it if new
     pub fn add_lru(self: &mut LruQueue<T>, new_element: &T) {
-        // Check if element is already in vec, then flip to the end
-        if let Some(position) = self.queue.iter().position(|elem| elem == new_element) {
-            let elem = self.queue.remove(position);
-            self.queue.push(elem);
-        } else {
-            // Otherwise, add to queue
-            self.queue.push(new_element.clone());
+        // Check if element is already in the queue, then flip to the end
+        if let Some(&slot) = self.index.get(new_element) {
+            self.unlink(slot);
+            self.link_last(slot);
+            return;
         }
+
+        // Otherwise, add to queue
+        let node = Node {
+            value: new_element.clone(),
+            prev: Self::NIL,
+            next: Self::NIL,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        self.index.insert(new_element.clone(), slot);
+        self.link_last(slot);
     }
 
     pub fn evict_lru(self: &mut LruQueue<T>) -> Option<T> {
-        if self.queue.len() > 0 {
-            let result = self.queue.remove(0);
-            return Some(result);
+        if self.head == Self::NIL {
+            return None;
         }
-
-        None
+        Some(self.remove_slot(self.head))
     }
 
     pub fn evict_lru_by_value(self: &mut LruQueue<T>, value: &T) -> Option<T> {
-        let position = self.queue.iter().position(|x| x == value)?;
-        let result = self.queue.remove(position);
-        Some(result)
+        let slot = *self.index.get(value)?;
+        Some(self.remove_slot(slot))
+    }
+
+    // The least recently used element, left in place
+    pub fn peek_lru(self: &LruQueue<T>) -> Option<&T> {
+        self.nodes.get(self.head)?.as_ref().map(|node| &node.value)
+    }
+
+    pub fn contains(self: &LruQueue<T>, value: &T) -> bool {
+        self.index.contains_key(value)
+    }
+
+    pub fn len(self: &LruQueue<T>) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(self: &LruQueue<T>) -> bool {
+        self.index.is_empty()
+    }
+
+    // Take a node out of the list and free its slot
+    fn remove_slot(self: &mut LruQueue<T>, slot: usize) -> T {
+        self.unlink(slot);
+        let node = self.nodes[slot].take().expect("linked slot holds a node");
+        self.free.push(slot);
+        self.index.remove(&node.value);
+        node.value
+    }
+
+    fn node(self: &mut LruQueue<T>, slot: usize) -> &mut Node<T> {
+        self.nodes[slot].as_mut().expect("linked slot holds a node")
+    }
+
+    // Detach a node from its neighbours, joining them up
+    fn unlink(self: &mut LruQueue<T>, slot: usize) {
+        let Node { prev, next, .. } = *self.node(slot);
+        match prev {
+            Self::NIL => self.head = next,
+            prev => self.node(prev).next = next,
+        }
+        match next {
+            Self::NIL => self.tail = prev,
+            next => self.node(next).prev = prev,
+        }
+    }
+
+    // Attach a detached node at the most recently used end
+    fn link_last(self: &mut LruQueue<T>, slot: usize) {
+        let tail = self.tail;
+        let node = self.node(slot);
+        node.prev = tail;
+        node.next = Self::NIL;
+        match tail {
+            Self::NIL => self.head = slot,
+            tail => self.node(tail).next = slot,
+        }
+        self.tail = slot;
     }
 }
@@ -1,41 +1,38 @@
 mod cache;
+mod cache_key;
+mod config;
+mod epoll;
+mod eviction;
 mod http_parser;
+mod lfu;
 mod lru_queue;
+mod method;
 mod proxy;
+mod reactor;
 mod request;
 mod response;
+mod sieve;
+mod thread_pool;
+mod tiny_lfu;
+mod tunnel;
+mod upstream_pool;
+mod url;
+mod version;
+mod error;
 mod headers;
 
+use crate::config::Config;
 use crate::proxy::Proxy;
 use std::env;
 use std::error::Error;
+use std::sync::Arc;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
-
-    let mut port = 0u16;
-    let mut does_cache = false;
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "-p" => {
-                if i + 1 >= args.len() {
-                    return Err("-p need a port".into());
-                }
-                port = args[i + 1].parse::<u16>()?;
-                i += 2;
-            }
-            "-c" => {
-                does_cache = true;
-                i += 1;
-            }
-            _ => {
-                return Err(format!("unknown argument {}", args[i]).into());
-            }
-        }
-    }
+    let config = Config::from_args(&args)?;
+    let port = config.port;
 
     // # 772, no global panic catch then
-    let mut proxy = Proxy::new(does_cache);
+    let proxy = Arc::new(Proxy::new(config));
     proxy.start_server(port)
 }
@@ -0,0 +1,78 @@
+use crate::error::ProxyError;
+use crate::headers;
+use std::fmt;
+
+// Request method (RFC 9110 section 9). Extension methods are kept by name.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Other(String),
+}
+
+impl Method {
+    // Methods are case-sensitive tokens
+    pub fn parse(method: &str) -> Result<Self, ProxyError> {
+        Ok(match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "CONNECT" => Method::Connect,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "PATCH" => Method::Patch,
+            method if headers::is_token(method) => Method::Other(method.to_string()),
+            method => return Err(ProxyError::BadRequest(format!("invalid method {}", method))),
+        })
+    }
+
+    pub fn as_str(self: &Method) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+            Method::Other(method) => method,
+        }
+    }
+
+    // Whether sending the request twice has the same effect as once
+    pub fn is_idempotent(self: &Method) -> bool {
+        matches!(
+            self,
+            Method::Get | Method::Head | Method::Options | Method::Trace | Method::Put | Method::Delete
+        )
+    }
+
+    // Whether the request is read-only (RFC 9110 section 9.2.1). Anything
+    // else may change what the origin holds for the target.
+    pub fn is_safe(self: &Method) -> bool {
+        matches!(self, Method::Get | Method::Head | Method::Options | Method::Trace)
+    }
+
+    // Whether the method can be answered from the cache. Only GET responses
+    // are stored, HEAD is answered from them.
+    pub fn is_cacheable(self: &Method) -> bool {
+        matches!(self, Method::Get | Method::Head)
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
@@ -1,44 +1,470 @@
 use crate::cache::{Cache, CacheRecord};
+use crate::cache_key::CacheKey;
+use crate::config::{Config, ForwardedHeader, IoMode};
+use crate::error::ProxyError;
+use crate::eviction::{self, EvictionPolicy};
 use crate::headers;
 use crate::headers::CacheControlHeader;
-use crate::http_parser::HttpParser;
+use crate::http_parser::{self, BodyLength, HttpParser};
+use crate::method::Method;
+use crate::reactor::Reactor;
+use crate::request::Request;
+use crate::response::Response;
+use crate::thread_pool::ThreadPool;
+use crate::tunnel::Tunnel;
+use crate::upstream_pool::UpstreamPool;
+use crate::url::Url;
+use crate::version::Version;
 use std::error::Error;
-use std::io::Write;
-use std::net::{Shutdown, TcpListener, TcpStream};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+// What becomes of a client connection once a request has been served
+#[derive(PartialEq)]
+pub enum Persistence {
+    KeepAlive,
+    Close,
+    // Handed over to a tunnel, so it must not be shut down
+    Tunnelled,
+}
+
+// The cache with the eviction policy picked at startup
+type SharedCache = Cache<Box<dyn EvictionPolicy<CacheKey> + Send>>;
 
 pub struct Proxy {
-    does_cache: bool,
-    cache: Cache,
+    config: Config,
+    // Shared by all workers. Log lines about the cache are printed while the
+    // lock is held, so they stay in the order the cache changed.
+    cache: Mutex<SharedCache>,
+    upstream_pool: UpstreamPool,
 }
 
 impl Proxy {
     const TAIL_OFFSET: usize = 3;
-    const REQUEST_CACHE_LENGTH: usize = 2000;
-    const RESPONSE_CACHE_LENGTH: usize = 100 * 1024;
-    const NOT_MODIFIED_STATUS_CODE: &'static str = "304";
+    const CONNECTION_ESTABLISHED: &'static [u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+    const CONTINUE: &'static [u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+    // Name this proxy goes by in Via headers
+    const VIA_PSEUDONYM: &'static str = "htproxy";
 
-    pub fn new(does_cache: bool) -> Self {
+    pub fn new(config: Config) -> Self {
         Self {
-            does_cache,
-            cache: Cache::new(),
+            cache: Mutex::new(Cache::new(
+                eviction::new_policy(config.eviction),
+                config.cache_size,
+                config.cache_object_size,
+            )),
+            upstream_pool: UpstreamPool::new(
+                config.upstream_max_idle,
+                config.upstream_idle_timeout,
+                config.connect_timeout,
+            ),
+            config,
         }
     }
 
-    fn handle_connection(self: &mut Proxy, mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
-        // No need for SO_REUSEADDR as set by default
-        stream.set_nodelay(true)?;
-        println!("Accepted");
+    // Parser for the requests of a client connection, starting with bytes
+    // already read off it
+    pub fn request_parser<R: Read>(self: &Proxy, stream: R, unread: Vec<u8>) -> HttpParser<R> {
+        let mut parser = HttpParser::with_buffer(stream, unread);
+        parser.set_head_timeout(Some(self.config.header_timeout));
+        parser.set_obs_fold(self.config.obs_fold);
+        parser
+    }
+
+    fn lock_cache(self: &Proxy) -> Result<MutexGuard<'_, SharedCache>, ProxyError> {
+        self.cache
+            .lock()
+            .map_err(|_| ProxyError::Internal("cache lock poisoned".to_string()))
+    }
+
+    fn log_eviction(record: &CacheRecord) {
+        println!("Evicting {} {} from cache", record.host, record.target);
+    }
+
+    // After an unsafe request went through, drop the entries for its target and
+    // for the Location and Content-Location urls on the same origin (RFC 9111
+    // section 4.4), as they may no longer be what the origin holds
+    fn invalidate(self: &Proxy, url: &Url, response: &Response) -> Result<(), ProxyError> {
+        let mut urls = vec![url.clone()];
+        for name in [headers::LOCATION_HEADER, headers::CONTENT_LOCATION_HEADER] {
+            if let Some(location) = response.headers.get(name).and_then(|value| url.resolve(value)) {
+                if location.same_origin(url) && !urls.contains(&location) {
+                    urls.push(location);
+                }
+            }
+        }
+
+        let mut cache = self.lock_cache()?;
+        for url in &urls {
+            for record in cache.invalidate(url)? {
+                Self::log_eviction(&record);
+            }
+        }
+        Ok(())
+    }
+
+    // Park the upstream connection for the next request to the same origin if
+    // the whole request went out and the response left it clean, otherwise it
+    // is closed as it is dropped
+    fn release_upstream(
+        self: &Proxy,
+        url: &Url,
+        response_parser: HttpParser<TcpStream>,
+        response: &Response,
+        body_length: BodyLength,
+        request_complete: bool,
+    ) -> Result<(), ProxyError> {
+        if request_complete
+            && response.keeps_alive()
+            && body_length != BodyLength::UntilClose
+            && response_parser.is_body_complete()
+            && !response_parser.has_partial_message()
+        {
+            self.upstream_pool
+                .release(&url.host, url.port, response_parser.into_inner())?;
+        }
+        Ok(())
+    }
+
+    // Pass an interim (1xx) response from the origin on to the client, unless
+    // it is HTTP/1.0 and wouldn't understand one
+    fn relay_interim(
+        mut stream: &TcpStream,
+        request: &Request,
+        response: &Response,
+        head: String,
+    ) -> Result<(), ProxyError> {
+        if request.version == Version::Http10 {
+            return Ok(());
+        }
+        let head = headers::append_header(
+            headers::strip_hop_by_hop(head),
+            &(headers::VIA_HEADER.into()),
+            &Self::via(&response.version),
+        );
+        Ok(stream.write_all(head.as_bytes())?)
+    }
+
+    // Wait for the origin to ask for the body of a request sent with Expect:
+    // 100-continue, relaying any interim responses. Gives back the final
+    // response if the origin answers without wanting the body. An origin that
+    // doesn't know the expectation gets the body anyway after expect_timeout,
+    // the client being told to go on in its place.
+    fn await_continue(
+        self: &Proxy,
+        mut stream: &TcpStream,
+        request: &Request,
+        response_parser: &mut HttpParser<TcpStream>,
+    ) -> Result<Option<Response>, ProxyError> {
+        response_parser.get_ref().set_read_timeout(Some(self.config.expect_timeout))?;
+        let result = loop {
+            match response_parser.read_response_header() {
+                Ok(response) if response.is_interim() => {
+                    Self::relay_interim(stream, request, &response, response_parser.header_lines()?)?;
+                    if response.status == Response::CONTINUE {
+                        break Ok(None);
+                    }
+                }
+                Ok(response) => break Ok(Some(response)),
+                Err(err) if err.is_timeout() && !response_parser.has_partial_message() => {
+                    break stream.write_all(Self::CONTINUE).map(|()| None).map_err(ProxyError::from);
+                }
+                Err(err) => break Err(err),
+            }
+        };
+        response_parser.get_ref().set_read_timeout(Some(self.config.first_byte_timeout))?;
+        result
+    }
+
+    // The request body is over the configured limit, checked both against a
+    // declared Content-Length and as a body streams in
+    fn body_too_large(max_body_size: usize) -> ProxyError {
+        ProxyError::PayloadTooLarge(format!("request body is over {} bytes", max_body_size))
+    }
+
+    // Via value for a message of the given HTTP version passing through here
+    fn via(version: &Version) -> String {
+        format!("{} {}", version.number(), Self::VIA_PSEUDONYM)
+    }
+
+    // Add Via, and whichever forwarded headers are configured, to a request
+    // about to go upstream
+    fn add_forwarding_headers(
+        self: &Proxy,
+        mut request_headers: String,
+        stream: &TcpStream,
+        request: &Request,
+        url: &Url,
+    ) -> Result<String, ProxyError> {
+        request_headers = headers::append_header(
+            request_headers,
+            &(headers::VIA_HEADER.into()),
+            &Self::via(&request.version),
+        );
+        if self.config.forwarded_headers.is_empty() {
+            return Ok(request_headers);
+        }
 
-        // get request
-        let mut request_parser = HttpParser::new(&mut stream);
-        let request = request_parser.read_request()?;
+        // Listening on [::] gives IPv4 clients as mapped IPv6 addresses
+        let client = stream.peer_addr()?.ip().to_canonical();
+        for header in &self.config.forwarded_headers {
+            let (name, value) = match header {
+                ForwardedHeader::XForwardedFor => (headers::X_FORWARDED_FOR_HEADER, client.to_string()),
+                ForwardedHeader::XForwardedProto => (headers::X_FORWARDED_PROTO_HEADER, url.scheme.clone()),
+                ForwardedHeader::Forwarded if client.is_ipv6() => (
+                    headers::FORWARDED_HEADER,
+                    format!("for=\"[{}]\";proto={}", client, url.scheme),
+                ),
+                ForwardedHeader::Forwarded => (
+                    headers::FORWARDED_HEADER,
+                    format!("for={};proto={}", client, url.scheme),
+                ),
+            };
+
+            // Earlier proxies' entries come first, except for the scheme,
+            // which is only about the last hop
+            let mut values = headers::header_values(&request_headers, name);
+            if *header == ForwardedHeader::XForwardedProto {
+                values.clear();
+            }
+            values.push(value);
+            request_headers = headers::append_header(
+                headers::remove_header(request_headers, name),
+                &(name.into()),
+                &values.join(", "),
+            );
+        }
+        Ok(request_headers)
+    }
+
+    // Whether a response with this header has to go to the client without its
+    // chunk framing, which HTTP/1.0 clients can't read
+    fn unchunks(request: &Request, head: &str) -> bool {
+        request.version == Version::Http10
+            && headers::is_chunked(&headers::header_values(head, headers::TRANSFER_ENCODING_HEADER).join(", "))
+    }
+
+    // Write a response to the client with the Connection header this proxy
+    // decided on, as the origin's hop-by-hop headers have been stripped. A
+    // chunked body for an HTTP/1.0 client is sent decoded, with the connection
+    // closed after as the only way to tell where it ends. The caller sends the
+    // rest of a body not in response the same way (see unchunks).
+    fn send_response(
+        mut stream: &TcpStream,
+        response: &[u8],
+        request: &Request,
+        persistence: &mut Persistence,
+    ) -> Result<(), ProxyError> {
+        let head_length = response
+            .windows(http_parser::HEAD_END.len())
+            .position(|window| window == http_parser::HEAD_END)
+            .ok_or_else(|| ProxyError::Internal("response without a header end".to_string()))?
+            + http_parser::HEAD_END.len();
+        // HEAD is answered with the header alone, even from a stored GET response
+        let (head, body) = response.split_at(head_length);
+        let body = if request.method == Method::Head { &[] } else { body };
+
+        let mut head = String::from_utf8(head.to_vec())
+            .map_err(|_| ProxyError::Internal("response header is not valid UTF-8".to_string()))?;
+        let mut body = body.to_vec();
+        if Self::unchunks(request, &head) {
+            head = headers::remove_header(head, headers::TRANSFER_ENCODING_HEADER);
+            if !body.is_empty() {
+                body = http_parser::decode_chunked(&body)?;
+            }
+            *persistence = Persistence::Close;
+        }
+
+        let connection = match persistence {
+            Persistence::Close => headers::CLOSE_TOKEN,
+            // HTTP/1.0 clients only keep connections open when told so
+            Persistence::KeepAlive if request.version == Version::Http10 => headers::KEEP_ALIVE_TOKEN,
+            _ => {
+                stream.write_all(head.as_bytes())?;
+                return Ok(stream.write_all(&body)?);
+            }
+        };
+
+        let head = headers::append_header(
+            head,
+            &(headers::CONNECTION_FIELD.into()),
+            &(connection.into()),
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&body)?;
+        Ok(())
+    }
+
+    // Tell the client what went wrong, if it can still be told, and hand the
+    // error back. The connection is closed after either way.
+    pub fn send_error(mut stream: &TcpStream, err: ProxyError) -> ProxyError {
+        if let Some((status_code, reason)) = err.status() {
+            // Best effort, the client may be gone already
+            let _ = stream.write_all(&Response::error_response(status_code, reason, &err.to_string()));
+        }
+        err
+    }
+
+    // Open a tunnel to the CONNECT authority (host:port) and relay bytes both ways
+    fn handle_connect(
+        self: &Proxy,
+        mut stream: &TcpStream,
+        authority: &String,
+        leftover: Vec<u8>,
+    ) -> Result<Persistence, ProxyError> {
+        let (host, port) = Url::parse_authority(authority, None)
+            .map_err(|err| ProxyError::BadRequest(err.to_string()))?;
+        if !self.config.connect_ports.contains(&port) {
+            return Err(ProxyError::Forbidden(format!("tunnels to port {} are not allowed", port)));
+        }
+
+        println!("Tunnelling to {}", authority);
+        let (mut upstream, _) = self
+            .upstream_pool
+            .connect(&host, port, true)
+            .map_err(|err| ProxyError::from(err).upstream())?;
+        stream.write_all(Self::CONNECTION_ESTABLISHED)?;
+        // Client may have sent tunnel bytes along with the CONNECT request
+        upstream.write_all(&leftover)?;
+
+        Self::relay_in_background(stream, upstream, self.config.tunnel_idle_timeout, "Tunnel to", authority.clone())
+    }
+
+    // Relay bytes between the client and upstream until either side is done,
+    // logging the outcome as "<label> <target> ...". Tunnels are long lived,
+    // so keep them off the worker pool.
+    fn relay_in_background(
+        stream: &TcpStream,
+        upstream: TcpStream,
+        idle_timeout: Duration,
+        label: &'static str,
+        target: String,
+    ) -> Result<Persistence, ProxyError> {
+        let tunnel = Tunnel::new(stream.try_clone()?, upstream, idle_timeout);
+        thread::spawn(move || {
+            if let Err(err) = tunnel.relay() {
+                println!("{} {} error: {}", label, target, err);
+            }
+            println!("{} {} closed", label, target);
+        });
+        Ok(Persistence::Tunnelled)
+    }
+
+    // The protocols out of an Upgrade list that are on the allowlist, either by
+    // name or as name/version, joined back into a list
+    fn allowed_upgrades(self: &Proxy, offered: &str) -> String {
+        offered
+            .split(',')
+            .map(str::trim)
+            .filter(|protocol| {
+                let protocol = protocol.to_lowercase();
+                let name = protocol.split('/').next().unwrap_or_default();
+                self.config
+                    .upgrade_protocols
+                    .iter()
+                    .any(|allowed| *allowed == protocol || allowed == name)
+            })
+            .collect::<Vec<&str>>()
+            .join(", ")
+    }
+
+    // The origin agreed to switch protocols: pass its 101 on and relay bytes
+    // both ways from here, like a tunnel
+    fn switch_protocols(
+        self: &Proxy,
+        mut stream: &TcpStream,
+        request_parser: &mut HttpParser<&TcpStream>,
+        mut response_parser: HttpParser<TcpStream>,
+        response: &Response,
+        upgrade: Option<String>,
+        target: String,
+    ) -> Result<Persistence, ProxyError> {
+        let offered = upgrade
+            .ok_or_else(|| ProxyError::BadGateway("switching protocols unasked".to_string()))?;
+        let protocol = response
+            .headers
+            .get_combined(headers::UPGRADE_HEADER)
+            .ok_or_else(|| ProxyError::BadGateway("switching protocols without Upgrade".to_string()))?;
+        let is_offered = protocol
+            .split(',')
+            .all(|protocol| headers::has_token(&offered, protocol.trim()));
+        if !is_offered {
+            return Err(ProxyError::BadGateway(format!("switching to {} which was not offered", protocol)));
+        }
+
+        let response_head = headers::strip_hop_by_hop(response_parser.header_lines().map_err(ProxyError::upstream)?);
+        let response_head = headers::append_header(
+            response_head,
+            &(headers::VIA_HEADER.into()),
+            &Self::via(&response.version),
+        );
+        let response_head = headers::append_header(
+            response_head,
+            &(headers::CONNECTION_FIELD.into()),
+            &(headers::UPGRADE_TOKEN.into()),
+        );
+        let response_head = headers::append_header(
+            response_head,
+            &(headers::UPGRADE_HEADER.into()),
+            &protocol,
+        );
+
+        println!("Upgrading {} to {}", target, protocol);
+        stream.write_all(response_head.as_bytes())?;
+        // Either side may have sent the new protocol's first bytes already
+        stream.write_all(&response_parser.take_buffer())?;
+        let mut upstream = response_parser.into_inner();
+        upstream
+            .write_all(&request_parser.take_buffer())
+            .map_err(|err| ProxyError::from(err).interrupted())?;
+
+        Self::relay_in_background(stream, upstream, self.config.upgrade_idle_timeout, "Upgrade of", target)
+    }
 
-        // need to keep the original for cache indexing
-        let mut request_headers = request_parser.header_lines()?;
-        let original_request_headers = request_parser.header_lines()?;
+    // Serve the request at the front of unread, for a connection the reactor
+    // found ready, followed by any pipelined requests already buffered behind
+    // it, in order. Gives back the connection's fate, its unread bytes and the
+    // number of requests served on it so far.
+    pub fn handle_ready_requests(
+        self: &Proxy,
+        stream: &TcpStream,
+        unread: Vec<u8>,
+        mut served: usize,
+    ) -> Result<(Persistence, Vec<u8>, usize), ProxyError> {
+        stream.set_nodelay(true)?;
+        let mut request_parser = self.request_parser(stream, unread);
+        loop {
+            let request = request_parser
+                .read_request()
+                .map_err(|err| Self::send_error(stream, err))?;
+            served += 1;
+            // The last request allowed gets its response with Connection: close
+            let is_last = served >= self.config.max_requests;
+            let persistence = self
+                .serve_request(stream, &mut request_parser, request, is_last)
+                .map_err(|err| Self::send_error(stream, err))?;
+            // Anything not yet complete is left for the reactor to wait on
+            if persistence != Persistence::KeepAlive || !request_parser.has_buffered_head() {
+                return Ok((persistence, request_parser.take_buffer(), served));
+            }
+        }
+    }
 
-        let lines = request_headers
-            .split(HttpParser::CRLF)
+    // Serve a single request whose header was just read by request_parser,
+    // closing the connection after it if is_last
+    fn serve_request(
+        self: &Proxy,
+        mut stream: &TcpStream,
+        request_parser: &mut HttpParser<&TcpStream>,
+        request: Request,
+        is_last: bool,
+    ) -> Result<Persistence, ProxyError> {
+        let header_lines = request_parser.header_lines()?;
+        let lines = header_lines
+            .split(http_parser::CRLF)
             .collect::<Vec<&str>>();
 
         // If length less than 3 (TAIL_OFFSET), error
@@ -46,26 +472,112 @@ impl Proxy {
             "Request tail {}",
             lines
                 .get(lines.len() - Self::TAIL_OFFSET)
-                .ok_or("Unexpected format for request headers")?
+                .ok_or_else(|| ProxyError::BadRequest("Unexpected format for request headers".to_string()))?
         );
 
-        let request_host = request.get_host()?;
-        // Already throw if can't get url
+        if request.method == Method::Connect {
+            let leftover = request_parser.take_buffer();
+            return self.handle_connect(stream, &request.url, leftover);
+        }
+
+        // From here on neither side may stall for longer than the body timeout
+        stream.set_read_timeout(Some(self.config.body_timeout))?;
+        stream.set_write_timeout(Some(self.config.body_timeout))?;
+
+        // Already throw if can't get url, or it disagrees with Host
+        let url = request.target()?;
+        let request_host = request
+            .get_host()
+            .unwrap_or_else(|_| url.host_header());
         let request_url = request.url.clone();
+
+        // Forward in origin-form with a Host header and without hop-by-hop
+        // headers
+        let mut request_headers = headers::strip_hop_by_hop(headers::replace_request_target(
+            header_lines,
+            &url.origin_form(),
+        ));
+        if request.get_host().is_err() {
+            request_headers = headers::append_header(
+                request_headers,
+                &(headers::HOST_HEADER.into()),
+                &url.host_header(),
+            );
+        }
+        request_headers = self.add_forwarding_headers(request_headers, stream, &request, &url)?;
+
+        // Only allowed protocols are asked of the origin, the Upgrade and
+        // Connection headers having been stripped as hop-by-hop. Without any,
+        // the request goes on as a plain one.
+        let upgrade = match request.upgrade() {
+            Some(offered) if self.allowed_upgrades(&offered).is_empty() => {
+                if self.config.strict_upgrade {
+                    return Err(ProxyError::Forbidden(format!("upgrades to {} are not allowed", offered)));
+                }
+                None
+            }
+            Some(offered) => {
+                let allowed = self.allowed_upgrades(&offered);
+                request_headers = headers::append_header(
+                    request_headers,
+                    &(headers::CONNECTION_FIELD.into()),
+                    &(headers::UPGRADE_TOKEN.into()),
+                );
+                request_headers = headers::append_header(
+                    request_headers,
+                    &(headers::UPGRADE_HEADER.into()),
+                    &allowed,
+                );
+                Some(allowed)
+            }
+            None => None,
+        };
+
+        // Reject early if the declared body is too large to forward
+        let request_body_length = request.body_length()?;
+        if let BodyLength::ContentLength(content_length) = request_body_length {
+            if content_length > self.config.max_body_size {
+                return Err(Self::body_too_large(self.config.max_body_size));
+            }
+        }
+        if let Some(expectation) = request.unmet_expectation() {
+            return Err(ProxyError::ExpectationFailed(format!("expectation {} is not supported", expectation)));
+        }
+        request_parser.start_body(request_body_length);
+        let mut persistence = if request.keeps_alive() && !is_last {
+            Persistence::KeepAlive
+        } else {
+            Persistence::Close
+        };
+
         let mut is_expired = false;
         let mut option_cache_record: Option<CacheRecord> = None;
 
-        if self.does_cache && request_headers.len() < Self::REQUEST_CACHE_LENGTH {
+        // Absolute and origin-form requests for the same url share entries
+        let resource_key = CacheKey::new(&request, &url);
+        let mut lookup_key = resource_key.clone();
+        // A handshake is never answered from, or stored in, the cache
+        let is_cacheable = self.config.does_cache
+            && request.method.is_cacheable()
+            && upgrade.is_none();
+        if is_cacheable {
             // check cache
-            if let Some((cache_value, local_is_expired)) = self.cache.get(&request_headers) {
+            let mut cache = self.lock_cache()?;
+            let found = cache
+                .find_variant(&resource_key, &request.headers)
+                .and_then(|key| Some((cache.get(&key)?, key)));
+            if let Some(((cache_value, local_is_expired), key)) = found {
+                lookup_key = key;
                 is_expired = local_is_expired;
 
                 if !is_expired {
                     // use cache
                     println!("Serving {} {} from cache", request_host, request_url);
-                    stream.write_all(&cache_value.response)?;
-                    stream.shutdown(Shutdown::Both)?;
-                    return Ok(());
+                    drop(cache);
+                    // Skip any request body, the next request follows it
+                    while request_parser.read_body(0)?.is_some() {}
+                    Self::send_response(stream, &cache_value.response, &request, &mut persistence)?;
+                    return Ok(persistence);
                 } else {
                     // Logging for task 4
                     println!("Stale entry for {} {}", request_host, request_url);
@@ -82,67 +594,146 @@ impl Proxy {
                 }
 
                 option_cache_record = Some(cache_value);
-            } else {
-                // evict if full, task 2
-                if self.cache.is_full() {
-                    let record = self.cache.remove_lru_cache()?;
-                    println!(
-                        "Evicting {} {} from cache",
-                        record.request.get_host()?,
-                        record.request.url
-                    );
-                }
             }
         }
 
         println!("GETting {} {}", request_host, request_url);
 
-        // create remote server socket and forward request
-        let mut proxy = TcpStream::connect(format!("{}:80", request_host))?;
-        proxy.set_nodelay(true)?;
-        proxy.write_all(&request_headers.as_bytes())?;
+        // Forward the request, on a pooled connection if there is one. The server
+        // may have closed that in the meantime, which only shows now, so a
+        // request that is safe to repeat gets another go on a fresh connection.
+        let can_retry = request.is_idempotent() && request_body_length == BodyLength::Empty;
+        let mut fresh = false;
+        let (mut response_parser, response) = loop {
+            let (upstream, reused) = self
+                .upstream_pool
+                .connect(&url.host, url.port, fresh)
+                .map_err(|err| ProxyError::from(err).upstream())?;
+            upstream
+                .set_read_timeout(Some(self.config.first_byte_timeout))
+                .and_then(|()| upstream.set_write_timeout(Some(self.config.body_timeout)))
+                .map_err(|err| ProxyError::from(err).upstream())?;
+            let mut response_parser = HttpParser::new(upstream);
+            response_parser.set_obs_fold(self.config.obs_fold);
+            let mut sent = response_parser.get_ref().write_all(request_headers.as_bytes());
+
+            // With Expect: 100-continue the origin may turn the body down first
+            let mut early_response = None;
+            if sent.is_ok() && request.expects_continue() && request_body_length != BodyLength::Empty {
+                early_response = self
+                    .await_continue(stream, &request, &mut response_parser)
+                    .map_err(ProxyError::upstream)?;
+            }
+
+            // stream the request body, if any, checking the size as chunks arrive
+            if sent.is_ok() && early_response.is_none() {
+                while let Some(bytes) = request_parser.read_body(0)? {
+                    if request_parser.body_size() > self.config.max_body_size {
+                        return Err(Self::body_too_large(self.config.max_body_size));
+                    }
+                    sent = response_parser.get_ref().write_all(&bytes);
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+            }
+
+            // read server header, passing interim responses on
+            if let Some(response) = early_response {
+                break (response_parser, response);
+            }
+            let result = sent
+                .map_err(ProxyError::from)
+                .and_then(|()| {
+                    response_parser.read_final_response_header(|response, head| {
+                        Self::relay_interim(stream, &request, response, head)
+                    })
+                })
+                .map_err(ProxyError::upstream);
+            match result {
+                Ok(response) => break (response_parser, response),
+                // A slow origin isn't a stale connection, so it isn't retried
+                Err(err) if !err.is_timeout()
+                    && reused
+                    && can_retry
+                    && !response_parser.has_partial_message() =>
+                {
+                    println!("Retrying {} {}: {}", request_host, request_url, err);
+                    fresh = true;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        if response.status == Response::SWITCHING_PROTOCOLS {
+            let target = format!("{} {}", request_host, request_url);
+            return self.switch_protocols(stream, request_parser, response_parser, &response, upgrade, target);
+        }
 
-        // read server header
-        let mut response_parser = HttpParser::new(&mut proxy);
-        let response = response_parser.read_response_header()?;
+        // The client's body went unread if the origin answered before it, so the
+        // connection can't carry another request
+        let request_complete = request_parser.is_body_complete();
+        if !request_complete {
+            persistence = Persistence::Close;
+        }
+
+        // A non-error response means the unsafe request went through
+        if self.config.does_cache && !request.method.is_safe() && !response.is_error() {
+            self.invalidate(&url, &response)?;
+        }
 
         // Get status code for task 5. If 304, return early.
-        if self.does_cache && response.status_code == Self::NOT_MODIFIED_STATUS_CODE {
+        if self.config.does_cache && response.status == Response::NOT_MODIFIED {
             if let Some(cache_value) = option_cache_record {
                 // use cache and log
                 println!("Serving {} {} from cache", request_host, request.url);
-                stream.write_all(&cache_value.response)?;
+                Self::send_response(stream, &cache_value.response, &request, &mut persistence)?;
 
                 if is_expired {
                     println!("Entry for {} {} unmodified", request_host, request.url);
                 }
 
-                stream.shutdown(Shutdown::Both)?;
-                proxy.shutdown(Shutdown::Both)?;
+                self.release_upstream(&url, response_parser, &response, BodyLength::Empty, request_complete)
+                    .map_err(ProxyError::interrupted)?;
 
-                return Ok(());
+                return Ok(persistence);
             }
         }
 
         // Otherwise, proxy and cache (if applicable)
-        // Get content length
-        let content_length = response
-            .headers
-            .get(headers::CONTENT_LENGTH_HEADER)
-            .ok_or("expected a content length in the response")?
-            .parse::<usize>()?;
-        println!("Response body length {}", content_length);
+        // Get body framing
+        let body_length = response.body_length(&request)?;
+        match body_length {
+            BodyLength::Empty => println!("Response body length 0"),
+            BodyLength::ContentLength(content_length) => {
+                println!("Response body length {}", content_length)
+            }
+            BodyLength::Chunked => println!("Response body chunked"),
+            BodyLength::UntilClose => println!("Response body until close"),
+        }
+
+        // Without framing the client can only tell the body ended by the close
+        if body_length == BodyLength::UntilClose || response.closes() {
+            persistence = Persistence::Close;
+        }
 
         // Get cache-control
         let mut allow_cache = true;
         let mut expiry_time = None;
-        if let Some(cache_control_val) = response.headers.get(headers::CACHE_CONTROL_HEADER) {
-            let cache_control = CacheControlHeader::new(cache_control_val)?;
+        // Every Cache-Control line counts, a no-store on the second is as binding
+        if let Some(cache_control_val) = response.headers.get_combined(headers::CACHE_CONTROL_HEADER) {
+            let cache_control = CacheControlHeader::new(&cache_control_val)
+                .map_err(|err| ProxyError::BadGateway(err.to_string()))?;
             allow_cache = cache_control.should_cache();
             if allow_cache {
                 expiry_time = cache_control.cache_expire();
             }
         };
+        // Vary: * means no later request can be told to match this one
+        let vary = response.vary();
+        if vary.iter().any(|name| name == headers::VARY_ANY) {
+            allow_cache = false;
+        }
 
         // Get date
         let date = response
@@ -150,75 +741,153 @@ impl Proxy {
             .get(headers::DATE_HEADER).cloned();
 
         // forward header
-        stream.write_all(&response_parser.data())?;
+        response_parser
+            .get_ref()
+            .set_read_timeout(Some(self.config.body_timeout))
+            .map_err(|err| ProxyError::from(err).upstream())?;
+        let raw_response_head = response_parser.header_lines().map_err(ProxyError::upstream)?;
+        let raw_response_head_length = raw_response_head.len();
+        let mut response_head = headers::strip_hop_by_hop(raw_response_head);
+        // The body is framed by Transfer-Encoding, so a Content-Length next to
+        // it must not travel on, nor into the cache (RFC 9112 section 6.1)
+        if response.headers.contains(headers::TRANSFER_ENCODING_HEADER) {
+            response_head = headers::remove_header(response_head, headers::CONTENT_LENGTH_HEADER);
+        }
+        let response_head = headers::append_header(
+            response_head,
+            &(headers::VIA_HEADER.into()),
+            &Self::via(&response.version),
+        );
+        Self::send_response(stream, response_head.as_bytes(), &request, &mut persistence)?;
 
-        // read and forward server response body
-        let mut count = 0;
-        while count < content_length {
-            let bytes = response_parser.read_bytes(Self::RESPONSE_CACHE_LENGTH)?;
+        // read and forward server response body. The client has part of the
+        // response from here on, so any error can only cut it off.
+        let unchunks = body_length == BodyLength::Chunked && Self::unchunks(&request, &response_head);
+        response_parser.start_body(body_length);
+        while let Some(bytes) = if unchunks {
+            response_parser.read_payload(self.config.cache_object_size)
+        } else {
+            response_parser.read_body(self.config.cache_object_size)
+        }
+        .map_err(|err| {
+                ProxyError::Interrupted(format!("{} body from origin: {}", response.status_line(), err.upstream()))
+            })?
+        {
             stream.write_all(&bytes)?;
-            count += bytes.len();
-            // eprint!("{count}");
-        }
-        stream.shutdown(Shutdown::Both)?;
-
-        let evict_if_expired = |proxy: &mut Self| -> Result<(), Box<dyn Error>> {
-            if is_expired {
-                let record = proxy.cache.remove_cache(&original_request_headers)?;
-                println!(
-                    "Evicting {} {} from cache",
-                    record.request.get_host()?,
-                    record.request.url
-                );
-            }
+        }
 
-            Ok(())
-        };
+        // Only complete bodies are cached, a close-delimited one is complete on
+        // a clean EOF. The stored header is the one sent on, without Connection.
+        // Past the size limit the parser stops storing, so the check is on its data.
+        let raw_response_data = response_parser.data();
+        let fits_cache = raw_response_data.len() <= self.config.cache_object_size;
+        let body = &raw_response_data[raw_response_head_length..];
+        // A stored close-delimited body is known whole, so it gets a length
+        // and can be served on a kept alive connection. One with a transfer
+        // coding other than chunked can't be given one, so it isn't stored.
+        let is_framed = body_length != BodyLength::UntilClose
+            || !response.headers.contains(headers::TRANSFER_ENCODING_HEADER);
+        let mut response_head = response_head;
+        if body_length == BodyLength::UntilClose && is_framed {
+            response_head = headers::append_header(
+                response_head,
+                &(headers::CONTENT_LENGTH_FIELD.into()),
+                &body.len().to_string(),
+            );
+        }
+        let mut response_data = response_head.into_bytes();
+        response_data.extend_from_slice(body);
+        let is_body_complete = response_parser.is_body_complete();
+        // The response is already with the client, so failing here only cuts it off
+        let store = || -> Result<(), ProxyError> {
+            // Another worker may have evicted the stale entry already
+            let evict_if_expired = |cache: &mut SharedCache| -> Result<(), ProxyError> {
+                if is_expired && cache.contains(&lookup_key) {
+                    Self::log_eviction(&cache.remove_cache(&lookup_key)?);
+                }
 
-        let response_data = response_parser.data();
-        if self.does_cache
-            && request_headers.len() < Self::REQUEST_CACHE_LENGTH
-            && response_data.len() <= Self::RESPONSE_CACHE_LENGTH
-        {
-            if !allow_cache {
-                println!("Not caching {} {}", request_host, request_url);
-                evict_if_expired(self)?;
-            } else {
-                // cache response
-                // Add cache will overwrite the old response,
-                // and add_lru will flip entries to the end if exist.
-                // So no need to evict (specs also don't allow log here)
-                self.cache.add_cache(
-                    original_request_headers,
-                    request,
-                    response_data,
-                    expiry_time,
-                    date,
-                )?;
+                Ok(())
+            };
+
+            // A HEAD response has no body to store for GET
+            if is_cacheable && request.method == Method::Get && is_body_complete && fits_cache && is_framed {
+                let mut cache = self.lock_cache()?;
+                if !allow_cache {
+                    println!("Not caching {} {}", request_host, request_url);
+                    evict_if_expired(&mut cache)?;
+                } else if !cache.admits(response_data.len()) {
+                    evict_if_expired(&mut cache)?;
+                } else {
+                    // Stored under the headers this response varies on. Older
+                    // variants this request selects are superseded by it.
+                    let cache_key = resource_key.with_vary(&vary, &request.headers);
+                    for key in cache.matching_variants(&cache_key.resource(), &request.headers) {
+                        if key != cache_key {
+                            Self::log_eviction(&cache.remove_cache(&key)?);
+                        }
+                    }
+
+                    // Make room, in the order the eviction policy picks
+                    while !cache.has_room(&cache_key, response_data.len()) {
+                        Self::log_eviction(&cache.evict()?);
+                    }
+
+                    // cache response
+                    // Add cache will overwrite the old response,
+                    // and the policy counts that as a use of the entry.
+                    // So no need to evict (specs also don't allow log here)
+                    cache.add_cache(
+                        cache_key,
+                        request_host.clone(),
+                        request_url.clone(),
+                        response_data,
+                        expiry_time,
+                        date,
+                    )?;
+                }
+            } else if self.config.does_cache {
+                evict_if_expired(&mut *self.lock_cache()?)?;
             }
-        } else {
-            evict_if_expired(self)?;
-        }
+            Ok(())
+        };
+        store().map_err(ProxyError::interrupted)?;
 
-        // Close the server connection as well
-        proxy.shutdown(Shutdown::Both)?;
-        Ok(())
+        self.release_upstream(&url, response_parser, &response, body_length, request_complete)
+            .map_err(ProxyError::interrupted)?;
+        Ok(persistence)
     }
 
-    pub fn start_server(self: &mut Proxy, port: u16) -> Result<(), Box<dyn Error>> {
+    pub fn start_server(self: Arc<Proxy>, port: u16) -> Result<(), Box<dyn Error>> {
         // start listener
         // note that the default backlog is 128 in rust, and it cannot be changed
         let listener = TcpListener::bind(format!("[::]:{}", port))?;
+        let pool = Arc::new(ThreadPool::new(self.config.threads));
+        let keep_alive_timeout = self.config.keep_alive_timeout;
+        if self.config.io_mode == IoMode::Epoll {
+            return Reactor::new(self, pool, Some(listener), keep_alive_timeout)?.run();
+        }
+
+        // Connections wait on their first and later requests in a reactor of
+        // their own, so an idle client doesn't hold a worker
+        let reactor = Reactor::new(self.clone(), pool, None, keep_alive_timeout)?;
+        let handle = reactor.handle();
+        thread::spawn(move || {
+            if let Err(err) = reactor.run() {
+                println!("reactor error: {}", err);
+            }
+        });
+
         for stream in listener.incoming() {
-            let result = stream
-                .map_err(|e| e.into())
-                .and_then(|stream| self.handle_connection(stream));
-            match result {
-                Ok(()) => {}
+            let stream = match stream {
+                Ok(stream) => stream,
                 Err(err) => {
                     println!("handle_connection error: {}", err);
-                } // ignored errors
-            }
+                    continue;
+                }
+            };
+
+            println!("Accepted");
+            handle.watch(stream, Vec::new(), 0);
         }
 
         Ok(())
@@ -0,0 +1,311 @@
+use crate::epoll::{Epoll, EPOLLIN, EPOLLRDHUP};
+use crate::error::ProxyError;
+use crate::http_parser::HttpParser;
+use crate::proxy::{Persistence, Proxy};
+use crate::thread_pool::ThreadPool;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// A client connection waiting on its next request header
+struct Connection {
+    parser: HttpParser<TcpStream>,
+    last_active: Instant,
+    // requests served on it so far
+    served: usize,
+}
+
+impl Connection {
+    // When the connection is to be closed if nothing more happens: once its
+    // header is overdue, or once it has waited on a request for too long
+    fn deadline(self: &Connection, keep_alive_timeout: Duration) -> Option<Instant> {
+        if self.parser.has_partial_message() {
+            return self.parser.head_deadline();
+        }
+        Some(self.last_active + keep_alive_timeout)
+    }
+}
+
+// A connection handed to the reactor from another thread: kept alive by a
+// worker after serving a request, or newly accepted
+struct Returned {
+    stream: TcpStream,
+    unread: Vec<u8>,
+    served: usize,
+}
+
+// What other threads hand connections to the reactor through
+#[derive(Clone)]
+pub struct ReactorHandle {
+    // connections are sent over, then a byte is written to wake epoll
+    returned_sender: Sender<Returned>,
+    waker: Arc<UnixStream>,
+}
+
+impl ReactorHandle {
+    // Have the reactor wait on a connection's next request, unread holding
+    // bytes already read past the requests served
+    pub fn watch(self: &ReactorHandle, stream: TcpStream, unread: Vec<u8>, served: usize) {
+        let returned = Returned {
+            stream,
+            unread,
+            served,
+        };
+        if self.returned_sender.send(returned).is_ok() {
+            let _ = (&*self.waker).write(&[0]);
+        }
+    }
+
+    // Settle a connection a worker is done with: kept alive ones go back to
+    // wait on their next request, closed ones are shut down
+    pub fn finish(
+        self: &ReactorHandle,
+        stream: TcpStream,
+        result: Result<(Persistence, Vec<u8>, usize), ProxyError>,
+    ) {
+        match result {
+            Ok((Persistence::KeepAlive, unread, served)) => self.watch(stream, unread, served),
+            Ok((Persistence::Close, _, _)) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            Ok((Persistence::Tunnelled, _, _)) => {}
+            Err(err) => {
+                println!("handle_connection error: {}", err);
+            }
+        }
+    }
+}
+
+// Event-driven server core. One thread watches every client connection with
+// epoll and reads request headers without blocking, so an idle client costs a
+// parser rather than a thread. Once a whole header has arrived the connection
+// is handed to a worker to be served, and comes back here if kept alive.
+// Without a listener it only watches the connections handed to it.
+pub struct Reactor {
+    proxy: Arc<Proxy>,
+    pool: Arc<ThreadPool>,
+    epoll: Epoll,
+    listener: Option<TcpListener>,
+    keep_alive_timeout: Duration,
+    connections: HashMap<u64, Connection>,
+    // earliest deadline of the connections, when close_idle is next due
+    next_sweep: Option<Instant>,
+    next_token: u64,
+    handle: ReactorHandle,
+    returned_receiver: Receiver<Returned>,
+    wake_receiver: UnixStream,
+}
+
+impl Reactor {
+    const LISTENER_TOKEN: u64 = 0;
+    const WAKER_TOKEN: u64 = 1;
+    const WAKE_BUFFER_SIZE: usize = 64;
+
+    pub fn new(
+        proxy: Arc<Proxy>,
+        pool: Arc<ThreadPool>,
+        listener: Option<TcpListener>,
+        keep_alive_timeout: Duration,
+    ) -> io::Result<Self> {
+        let (waker, wake_receiver) = UnixStream::pair()?;
+        waker.set_nonblocking(true)?;
+        wake_receiver.set_nonblocking(true)?;
+
+        let epoll = Epoll::new()?;
+        if let Some(listener) = &listener {
+            listener.set_nonblocking(true)?;
+            epoll.add(listener.as_raw_fd(), Self::LISTENER_TOKEN, EPOLLIN)?;
+        }
+        epoll.add(wake_receiver.as_raw_fd(), Self::WAKER_TOKEN, EPOLLIN)?;
+
+        let (returned_sender, returned_receiver) = mpsc::channel();
+        Ok(Self {
+            proxy,
+            pool,
+            epoll,
+            listener,
+            keep_alive_timeout,
+            connections: HashMap::new(),
+            next_sweep: None,
+            next_token: Self::WAKER_TOKEN + 1,
+            handle: ReactorHandle {
+                returned_sender,
+                waker: Arc::new(waker),
+            },
+            returned_receiver,
+            wake_receiver,
+        })
+    }
+
+    pub fn handle(self: &Reactor) -> ReactorHandle {
+        self.handle.clone()
+    }
+
+    pub fn run(mut self: Reactor) -> Result<(), Box<dyn Error>> {
+        loop {
+            // Sleep no longer than until the next connection runs out of time
+            let timeout = self
+                .next_sweep
+                .map(|next_sweep| next_sweep.saturating_duration_since(Instant::now()));
+            for token in self.epoll.wait(timeout)? {
+                match token {
+                    Self::LISTENER_TOKEN => self.accept(),
+                    Self::WAKER_TOKEN => self.take_returned(),
+                    // Errors and hang ups show up on the next read as well
+                    token => self.read(token),
+                }
+            }
+
+            if self.next_sweep.is_some_and(|next_sweep| Instant::now() >= next_sweep) {
+                self.close_idle();
+            }
+        }
+    }
+
+    // Accept every pending connection
+    fn accept(self: &mut Reactor) {
+        loop {
+            let Some(listener) = &self.listener else {
+                return;
+            };
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    println!("Accepted");
+                    if let Err(err) = self.register(stream, Vec::new(), 0) {
+                        println!("handle_connection error: {}", err);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    println!("handle_connection error: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Watch a connection for its next request. Bytes already read may hold a
+    // whole request, which epoll would never report, so try parsing right away.
+    fn register(self: &mut Reactor, stream: TcpStream, unread: Vec<u8>, served: usize) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        let token = self.next_token;
+        self.next_token += 1;
+        self.epoll
+            .add(stream.as_raw_fd(), token, EPOLLIN | EPOLLRDHUP)?;
+        let connection = Connection {
+            parser: self.proxy.request_parser(stream, unread),
+            last_active: Instant::now(),
+            served,
+        };
+        self.schedule(connection.deadline(self.keep_alive_timeout));
+        self.connections.insert(token, connection);
+        self.read(token);
+        Ok(())
+    }
+
+    // Register the connections handed over from other threads
+    fn take_returned(self: &mut Reactor) {
+        let mut wake_buffer = [0; Self::WAKE_BUFFER_SIZE];
+        while matches!(self.wake_receiver.read(&mut wake_buffer), Ok(bytes_read) if bytes_read > 0) {}
+
+        while let Ok(returned) = self.returned_receiver.try_recv() {
+            if let Err(err) = self.register(returned.stream, returned.unread, returned.served) {
+                println!("handle_connection error: {}", err);
+            }
+        }
+    }
+
+    // Have close_idle run by deadline at the latest
+    fn schedule(self: &mut Reactor, deadline: Option<Instant>) {
+        self.next_sweep = match (self.next_sweep, deadline) {
+            (Some(next_sweep), Some(deadline)) => Some(next_sweep.min(deadline)),
+            (next_sweep, deadline) => next_sweep.or(deadline),
+        };
+    }
+
+    // Close connections that have been waiting on a request for too long, and
+    // answer 408 to those whose header has been trickling in for too long
+    fn close_idle(self: &mut Reactor) {
+        let now = Instant::now();
+        let keep_alive_timeout = self.keep_alive_timeout;
+        let idle_tokens = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| {
+                connection
+                    .deadline(keep_alive_timeout)
+                    .is_some_and(|deadline| now >= deadline)
+            })
+            .map(|(token, _)| *token)
+            .collect::<Vec<u64>>();
+        for token in idle_tokens {
+            if let Some(connection) = self.connections.remove(&token) {
+                let stream = connection.parser.get_ref();
+                let _ = self.epoll.delete(stream.as_raw_fd());
+                if connection.parser.is_head_overdue() {
+                    let err = ProxyError::RequestTimeout("header took too long to arrive".to_string());
+                    println!("handle_connection error: {}", Proxy::send_error(stream, err));
+                }
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        self.next_sweep = self
+            .connections
+            .values()
+            .filter_map(|connection| connection.deadline(keep_alive_timeout))
+            .min();
+    }
+
+    // Feed newly arrived bytes to the connection's parser, handing the
+    // connection off once its request header is complete
+    fn read(self: &mut Reactor, token: u64) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        let result = connection.parser.read_request();
+        if let Err(ProxyError::Io(err)) = &result {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                // Partial header, the parser resumes on the next event and
+                // the header timeout bounds how long that may go on
+                let deadline = connection.deadline(self.keep_alive_timeout);
+                self.schedule(deadline);
+                return;
+            }
+        }
+
+        let Some(connection) = self.connections.remove(&token) else {
+            return;
+        };
+        let _ = self.epoll.delete(connection.parser.get_ref().as_raw_fd());
+        if let Err(err) = result {
+            // Closed between requests otherwise, where nothing is lost. The
+            // socket is non-blocking, but an error response fits its send buffer.
+            if !matches!(err, ProxyError::Io(_)) || connection.parser.has_partial_message() {
+                let stream = connection.parser.get_ref();
+                println!("handle_connection error: {}", Proxy::send_error(stream, err));
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            return;
+        }
+
+        // The worker parses the request again from the bytes read so far
+        let served = connection.served;
+        let (stream, unread) = connection.parser.into_parts();
+        let proxy = self.proxy.clone();
+        let handle = self.handle();
+        self.pool.execute(move || {
+            let result = stream
+                .set_nonblocking(false)
+                .map_err(|e| e.into())
+                .and_then(|()| proxy.handle_ready_requests(&stream, unread, served));
+            handle.finish(stream, result);
+        });
+    }
+}
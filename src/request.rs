@@ -1,59 +1,143 @@
-use crate::http_parser::HttpParser;
-use std::collections::HashMap;
-use std::error::Error;
+use crate::error::ProxyError;
+use crate::headers::{self, Headers};
+use crate::http_parser::{self, BodyLength};
+use crate::method::Method;
+use crate::url::Url;
+use crate::version::Version;
 
 #[derive(Clone)]
 pub struct Request {
+    pub method: Method,
     pub url: String,
-    pub headers: HashMap<String, String>,
+    pub version: Version,
+    pub headers: Headers,
 }
 
 impl Request {
-    const HEADER_PARTS: usize = 2;
     const HOST: &'static str = "host";
+    const REQUEST_LINE_PARTS: usize = 3;
 
-    pub fn get_host(self: &Request) -> Result<String, Box<dyn Error>> {
+    pub fn get_host(self: &Request) -> Result<String, ProxyError> {
         let host_val = self.headers.get(Request::HOST)
-            .ok_or("Host header not found")?
+            .ok_or_else(|| ProxyError::BadRequest("Host header not found".to_string()))?
             .clone();
         Ok(host_val)
     }
 
-    pub fn from_string(request: String) -> Result<Self, Box<dyn Error>> {
-        let mut headers = HashMap::new();
+    // Whether the client wants the connection kept open after this request.
+    // HTTP/1.1 defaults to persistent, HTTP/1.0 needs an explicit keep-alive.
+    pub fn keeps_alive(self: &Request) -> bool {
+        let connection = self.headers.get_combined(headers::CONNECTION_HEADER);
+        if connection.as_ref().is_some_and(|value| headers::has_token(value, headers::CLOSE_TOKEN)) {
+            return false;
+        }
+        if self.version == Version::Http10 {
+            return connection.is_some_and(|value| headers::has_token(&value, headers::KEEP_ALIVE_TOKEN));
+        }
+        true
+    }
+
+    // Whether sending the request twice has the same effect as once
+    pub fn is_idempotent(self: &Request) -> bool {
+        self.method.is_idempotent()
+    }
+
+    // Protocols the client offers to switch to, if it asks for an upgrade.
+    // Upgrade only counts when listed in Connection, and not at all in HTTP/1.0.
+    pub fn upgrade(self: &Request) -> Option<String> {
+        if self.version == Version::Http10 {
+            return None;
+        }
+        let connection = self.headers.get_combined(headers::CONNECTION_HEADER)?;
+        if !headers::has_token(&connection, headers::UPGRADE_TOKEN) {
+            return None;
+        }
+        self.headers.get_combined(headers::UPGRADE_HEADER)
+    }
+
+    // Expectations other than 100-continue, which this proxy can't meet
+    pub fn unmet_expectation(self: &Request) -> Option<String> {
+        let expect = self.headers.get_combined(headers::EXPECT_HEADER)?;
+        let unmet = expect
+            .split(',')
+            .map(str::trim)
+            .filter(|expectation| !expectation.eq_ignore_ascii_case(headers::CONTINUE_EXPECTATION))
+            .collect::<Vec<&str>>();
+        (!unmet.is_empty()).then(|| unmet.join(", "))
+    }
 
-        // first line is special
+    // Whether the client waits for 100 Continue before sending its body.
+    // Expect is ignored in HTTP/1.0 (RFC 9110 section 10.1.1).
+    pub fn expects_continue(self: &Request) -> bool {
+        self.version != Version::Http10
+            && self
+                .headers
+                .get_combined(headers::EXPECT_HEADER)
+                .is_some_and(|expect| headers::has_token(&expect, headers::CONTINUE_EXPECTATION))
+    }
+
+    // Scheme, host, port, path and query this request is for
+    pub fn target(self: &Request) -> Result<Url, ProxyError> {
+        Url::from_request(&self.url, self.headers.get(Request::HOST))
+            .map_err(|err| ProxyError::BadRequest(err.to_string()))
+    }
+
+    pub fn from_string(request: String) -> Result<Self, ProxyError> {
+        let mut headers = Headers::new();
+
+        // first line is special: method SP request-target SP HTTP-version
         let first = request
-            .split(HttpParser::CRLF)
+            .split(http_parser::CRLF)
             .nth(0)
-            .ok_or("error in parsing request first line")?;
-        let [_method, url, _format] = &first
-            .split(" ")
-            .into_iter()
-            .map(String::from)
-            .collect::<Vec<String>>()[..]
-        else {
-            return Err("error in parsing request first line".into());
-        };
-
-        for line in request.split(HttpParser::CRLF).skip(1) {
-            if line == "" {
+            .ok_or_else(|| ProxyError::BadRequest("error in parsing request first line".to_string()))?;
+        let parts = first.split(' ').collect::<Vec<&str>>();
+        if parts.len() != Self::REQUEST_LINE_PARTS {
+            return Err(ProxyError::BadRequest(format!("invalid request line {}", first)));
+        }
+        let method = Method::parse(parts[0])?;
+        let url = parts[1];
+        if url.is_empty() || url.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) {
+            return Err(ProxyError::BadRequest(format!("invalid request target {}", url)));
+        }
+        let version = Version::parse(parts[2])?;
+
+        for line in request.split(http_parser::CRLF).skip(1) {
+            if line.is_empty() {
                 break;
             }
 
             // parse header
-            if let [header, value] =
-                line.splitn(Self::HEADER_PARTS, ": ").collect::<Vec<&str>>()[..]
-            {
-                headers.insert(header.to_string().to_lowercase(), value.to_string());
-            } else {
-                return Err(format!("unknown header {}", line).into());
-            }
+            let (header, value) = headers::parse_field_line(line)?;
+            headers.append(header, value);
         }
 
         Ok(Request {
-            url: url.clone(),
+            method,
+            url: url.to_string(),
+            version,
             headers,
         })
     }
+
+    // Framing of the request body, following RFC 9112 section 6.3
+    pub fn body_length(self: &Request) -> Result<BodyLength, ProxyError> {
+        if let Some(encoding) = self.headers.get_combined(headers::TRANSFER_ENCODING_HEADER) {
+            // The origin may frame the body by the other header than this proxy
+            // did, and take the rest of it for another request (smuggling)
+            if self.headers.contains(headers::CONTENT_LENGTH_HEADER) {
+                return Err(ProxyError::BadRequest(
+                    "both Transfer-Encoding and Content-Length given".to_string(),
+                ));
+            }
+            if !headers::is_chunked(&encoding) {
+                return Err(ProxyError::BadRequest(format!("unsupported transfer encoding {}", encoding)));
+            }
+            return Ok(BodyLength::Chunked);
+        }
+
+        match self.headers.content_length()? {
+            Some(content_length) => Ok(BodyLength::ContentLength(content_length)),
+            None => Ok(BodyLength::Empty),
+        }
+    }
 }
@@ -1,63 +1,163 @@
-use crate::http_parser::HttpParser;
-use std::collections::HashMap;
-use std::error::Error;
-use crate::headers;
+use crate::error::ProxyError;
+use crate::headers::{self, Headers};
+use crate::http_parser::{self, BodyLength};
+use crate::method::Method;
+use crate::request::Request;
+use crate::version::Version;
 
 #[derive(Debug)]
 pub struct Response {
-    pub status_code: String,
-    pub headers: HashMap<String, String>,
+    pub version: Version,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
 }
 
 impl Response {
-    const RESPONSE_STATUS_CODE_POSITION: usize = 1;
-    const HEADER_PARTS: usize = 2;
-    pub fn from_string(response: String) -> Result<Self, Box<dyn Error>> {
-        let mut headers = HashMap::new();
-        let mut first_line = true;
-        let mut status_code: Option<String> = None;
-        // first line is special
-        for line in response.split(HttpParser::CRLF) {
-            // First line is special
-            if first_line {
-                let parts: Vec<&str> = line.split(" ").collect();
-                if Self::RESPONSE_STATUS_CODE_POSITION >= parts.len() {
-                    return Err("can't find status code".into());
-                }
-                status_code = Some(
-                    parts[Self::RESPONSE_STATUS_CODE_POSITION]
-                        .to_string()
-                        .clone(),
-                );
-                first_line = false;
-                continue;
-            }
+    pub const CONTINUE: u16 = 100;
+    pub const SWITCHING_PROTOCOLS: u16 = 101;
+    pub const NOT_MODIFIED: u16 = 304;
+    const STATUS_LINE_PARTS: usize = 3;
+    const STATUS_CODE_LENGTH: usize = 3;
+    const INFORMATIONAL_STATUS: std::ops::Range<u16> = 100..200;
+    const NO_BODY_STATUS_CODES: [u16; 2] = [204, 304];
+    const ERROR_STATUS_START: u16 = 400;
+
+    pub fn from_string(response: String) -> Result<Self, ProxyError> {
+        let mut lines = response.split(http_parser::CRLF);
+
+        // first line is special: HTTP-version SP status-code SP [reason-phrase]
+        let first = lines.next().unwrap_or_default();
+        let parts = first.splitn(Self::STATUS_LINE_PARTS, ' ').collect::<Vec<&str>>();
+        let [version, status, ref reason @ ..] = parts[..] else {
+            return Err(ProxyError::BadGateway(format!("invalid status line {}", first)));
+        };
+        let version = Version::parse(version).map_err(ProxyError::upstream)?;
+        if status.len() != Self::STATUS_CODE_LENGTH || !status.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ProxyError::BadGateway(format!("invalid status code {}", status)));
+        }
+        let status = status
+            .parse::<u16>()
+            .map_err(|_| ProxyError::BadGateway(format!("invalid status code {}", status)))?;
+        let reason = reason.first().unwrap_or(&"").to_string();
 
-            if line == "" {
+        let mut headers = Headers::new();
+        for line in lines {
+            if line.is_empty() {
                 break;
             }
 
             // parse header
-            if let [header, value] =
-                line.splitn(Self::HEADER_PARTS, ": ").collect::<Vec<&str>>()[..]
-            {
-                headers.insert(header.to_string().to_lowercase(), value.to_string());
-            } else {
-                return Err(format!("unknown header {}", line).into());
-            }
+            let (header, value) = headers::parse_field_line(line).map_err(ProxyError::upstream)?;
+            headers.append(header, value);
         }
 
-        let status_code = status_code.ok_or("error parsing status code")?;
-
         // If date is not in the header, use the default
         // TODO: Rust has no std date. How to get current date ???
-        if !headers.contains_key(headers::DATE_HEADER) {
-            headers.insert(headers::DATE_HEADER.to_string(), headers::DATE_HEADER_DEFAULT.to_string());
+        if !headers.contains(headers::DATE_HEADER) {
+            headers.append(headers::DATE_HEADER.to_string(), headers::DATE_HEADER_DEFAULT.to_string());
         }
 
         Ok(Response {
+            version,
+            status,
+            reason,
             headers,
-            status_code,
         })
     }
+
+    // Framing of the response body to the given request, following RFC 9112
+    // section 6.3. Responses to HEAD never carry a body, whatever they declare.
+    pub fn body_length(self: &Response, request: &Request) -> Result<BodyLength, ProxyError> {
+        if request.method == Method::Head
+            || Self::INFORMATIONAL_STATUS.contains(&self.status)
+            || Self::NO_BODY_STATUS_CODES.contains(&self.status)
+        {
+            return Ok(BodyLength::Empty);
+        }
+
+        // Transfer-Encoding overrides Content-Length. Without chunked as the
+        // final coding, the body runs until the connection closes.
+        if let Some(encoding) = self.headers.get_combined(headers::TRANSFER_ENCODING_HEADER) {
+            if !headers::is_chunked(&encoding) {
+                return Ok(BodyLength::UntilClose);
+            }
+            return Ok(BodyLength::Chunked);
+        }
+
+        match self.headers.content_length().map_err(ProxyError::upstream)? {
+            Some(content_length) => Ok(BodyLength::ContentLength(content_length)),
+            None => Ok(BodyLength::UntilClose),
+        }
+    }
+
+    // Whether the server will close the connection after this response
+    pub fn closes(self: &Response) -> bool {
+        self.headers
+            .get_combined(headers::CONNECTION_HEADER)
+            .is_some_and(|value| headers::has_token(&value, headers::CLOSE_TOKEN))
+    }
+
+    // Whether the server keeps the connection open after this response.
+    // HTTP/1.1 defaults to persistent, HTTP/1.0 needs an explicit keep-alive.
+    pub fn keeps_alive(self: &Response) -> bool {
+        if self.closes() {
+            return false;
+        }
+        if self.version == Version::Http10 {
+            return self
+                .headers
+                .get_combined(headers::CONNECTION_HEADER)
+                .is_some_and(|value| headers::has_token(&value, headers::KEEP_ALIVE_TOKEN));
+        }
+        true
+    }
+
+    // Whether this is an interim (1xx) response, with the final one still to
+    // come. A 101 ends HTTP on the connection, so it is the last one.
+    pub fn is_interim(self: &Response) -> bool {
+        Self::INFORMATIONAL_STATUS.contains(&self.status) && self.status != Self::SWITCHING_PROTOCOLS
+    }
+
+    // Lowercase names of the request headers the response varies on
+    pub fn vary(self: &Response) -> Vec<String> {
+        self.headers
+            .get_combined(headers::VARY_HEADER)
+            .map(|vary| {
+                vary.split(',')
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Whether the origin reported a client or server error
+    pub fn is_error(self: &Response) -> bool {
+        self.status >= Self::ERROR_STATUS_START
+    }
+
+    // Status code and reason phrase, as in "304 Not Modified"
+    pub fn status_line(self: &Response) -> String {
+        format!("{} {}", self.status, self.reason).trim_end().to_string()
+    }
+
+    // A minimal response the proxy answers with itself, closing the connection
+    // after. The body is a line of plain text saying what went wrong.
+    pub fn error_response(status_code: u16, reason: &str, detail: &str) -> Vec<u8> {
+        let body = format!("{}\n", detail);
+        format!(
+            "HTTP/1.1 {} {}{}Content-Type: text/plain{}Content-Length: {}{}Connection: close{}{}{}",
+            status_code,
+            reason,
+            http_parser::CRLF,
+            http_parser::CRLF,
+            body.len(),
+            http_parser::CRLF,
+            http_parser::CRLF,
+            http_parser::CRLF,
+            body
+        )
+        .into_bytes()
+    }
 }
@@ -0,0 +1,138 @@
+use crate::eviction::EvictionPolicy;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+// An entry of the queue, marked once read after it was stored
+struct Slot<T> {
+    key: T,
+    visited: bool,
+}
+
+// SIEVE eviction: entries queue up in the order they were stored and a hand
+// sweeps from the oldest to the newest, wrapping around. A visited entry the
+// hand passes loses its mark and stays; the first unmarked one is evicted.
+// Reads only set a mark, so hot entries cost nothing to keep.
+pub struct Sieve<T>
+where
+    T: Eq + Clone + Hash,
+{
+    // oldest first. Removed entries leave a None until the next compaction.
+    queue: Vec<Option<Slot<T>>>,
+    // position of each entry in queue
+    index: HashMap<T, usize>,
+    hand: usize,
+}
+
+impl<T> Sieve<T>
+where
+    T: Eq + Clone + Hash,
+{
+    pub fn new() -> Self {
+        Self {
+            queue: Vec::new(),
+            index: HashMap::new(),
+            hand: 0,
+        }
+    }
+
+    // Drop the holes once they make up half the queue, keeping the hand on
+    // the same entry
+    fn compact(self: &mut Sieve<T>) {
+        if self.queue.len() < 2 * self.index.len() {
+            return;
+        }
+
+        let hand = self.queue[..self.hand.min(self.queue.len())]
+            .iter()
+            .filter(|slot| slot.is_some())
+            .count();
+        self.queue.retain(Option::is_some);
+        for (position, slot) in self.queue.iter().enumerate() {
+            if let Some(slot) = slot {
+                self.index.insert(slot.key.clone(), position);
+            }
+        }
+        self.hand = hand;
+    }
+}
+
+impl<T> EvictionPolicy<T> for Sieve<T>
+where
+    T: Eq + Clone + Hash,
+{
+    fn insert(&mut self, key: &T) {
+        self.remove(key);
+        self.index.insert(key.clone(), self.queue.len());
+        self.queue.push(Some(Slot {
+            key: key.clone(),
+            visited: false,
+        }));
+    }
+
+    fn touch(&mut self, key: &T) {
+        if let Some(&position) = self.index.get(key) {
+            if let Some(slot) = &mut self.queue[position] {
+                slot.visited = true;
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &T) {
+        if let Some(position) = self.index.remove(key) {
+            self.queue[position] = None;
+            self.compact();
+        }
+    }
+
+    fn evict(&mut self) -> Option<T> {
+        if self.index.is_empty() {
+            return None;
+        }
+
+        // Every pass clears marks, so the second one at the latest finds a victim
+        loop {
+            if self.hand >= self.queue.len() {
+                self.hand = 0;
+            }
+            match &mut self.queue[self.hand] {
+                Some(slot) if slot.visited => {
+                    slot.visited = false;
+                    self.hand += 1;
+                }
+                Some(_) => break,
+                None => self.hand += 1,
+            }
+        }
+
+        let slot = self.queue[self.hand].take()?;
+        self.index.remove(&slot.key);
+        self.hand += 1;
+        self.compact();
+        Some(slot.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eviction::tests::{check_invariants, evict_all};
+
+    #[test]
+    fn sieve_invariants() {
+        check_invariants(Sieve::new());
+    }
+
+    #[test]
+    fn sieve_spares_visited_entries() {
+        let mut sieve = Sieve::new();
+        for key in 1..=3 {
+            sieve.insert(&key);
+        }
+        sieve.touch(&1);
+        // The hand passes 1, clearing its mark, and takes 2
+        assert_eq!(sieve.evict(), Some(2));
+        sieve.insert(&4);
+        // It carries on from where it stopped, coming back to 1 last
+        assert_eq!(evict_all(&mut sieve), vec![3, 4, 1]);
+    }
+}
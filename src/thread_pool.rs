@@ -0,0 +1,66 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Fixed set of worker threads taking jobs off a shared queue
+pub struct ThreadPool {
+    workers: Vec<JoinHandle<()>>,
+    sender: Option<Sender<Job>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || Self::work(&receiver))
+            })
+            .collect();
+
+        Self {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    // Queue a job for the next free worker
+    pub fn execute<F>(self: &ThreadPool, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &self.sender {
+            // Workers only stop once the sender is dropped, so this can't fail
+            let _ = sender.send(Box::new(job));
+        }
+    }
+
+    // Run jobs until the pool is dropped
+    fn work(receiver: &Mutex<Receiver<Job>>) {
+        loop {
+            // Guard is dropped before running the job, so others can take jobs
+            let job = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+            match job {
+                Ok(job) => job(),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(self: &mut ThreadPool) {
+        // Closing the queue stops the workers once they are done
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
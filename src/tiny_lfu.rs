@@ -0,0 +1,243 @@
+use crate::eviction::EvictionPolicy;
+use crate::lru_queue::LruQueue;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// Approximate use counts of keys in a bounded amount of memory. Each key bumps
+// one counter per row and its estimate is the smallest of those, so hash
+// collisions can only overestimate. Counters are halved every so often so
+// that old popularity fades. Rows are as wide as the number of entries
+// tracked, rounded up to a power of two.
+struct CountMinSketch {
+    rows: Vec<Vec<u8>>,
+    // increments since the counters were last halved
+    additions: usize,
+}
+
+impl CountMinSketch {
+    const DEPTH: usize = 4;
+    const MIN_WIDTH: usize = 16;
+    // 4 bit counters, as in TinyLFU
+    const MAX_COUNT: u8 = 15;
+    // Counters are halved after this many increments per column
+    const RESET_FACTOR: usize = 10;
+
+    fn new() -> Self {
+        Self::with_width(Self::MIN_WIDTH)
+    }
+
+    fn with_width(width: usize) -> Self {
+        Self {
+            rows: vec![vec![0; width]; Self::DEPTH],
+            additions: 0,
+        }
+    }
+
+    fn width(self: &CountMinSketch) -> usize {
+        self.rows[0].len()
+    }
+
+    // Widen the rows once there are more entries than columns. The counts
+    // can't be moved to their new columns, so they start over.
+    fn ensure_capacity(self: &mut CountMinSketch, entries: usize) {
+        if entries > self.width() {
+            *self = Self::with_width(entries.next_power_of_two());
+        }
+    }
+
+    // Column of the key in each row, by double hashing
+    fn columns<T: Hash>(self: &CountMinSketch, key: &T) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (first, second) = (hash as u32 as usize, (hash >> 32) as usize | 1);
+        let width = self.width();
+        (0..Self::DEPTH).map(move |row| first.wrapping_add(row.wrapping_mul(second)) % width)
+    }
+
+    fn increment<T: Hash>(self: &mut CountMinSketch, key: &T) {
+        for (row, column) in self.columns(key).enumerate() {
+            let counter = &mut self.rows[row][column];
+            *counter = (*counter + 1).min(Self::MAX_COUNT);
+        }
+
+        self.additions += 1;
+        if self.additions >= Self::RESET_FACTOR * self.width() {
+            for counter in self.rows.iter_mut().flatten() {
+                *counter /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn estimate<T: Hash>(self: &CountMinSketch, key: &T) -> u8 {
+        self.columns(key)
+            .enumerate()
+            .map(|(row, column)| self.rows[row][column])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+// W-TinyLFU eviction. New entries land in a small LRU window. The main area
+// behind it is a segmented LRU: entries start in probation and move to
+// protected when used again. When room is needed and the window is over its
+// share, its oldest entry is only admitted to the main area if the sketch has
+// seen it more often than the entry main would evict; the loser goes.
+pub struct WTinyLfu<T>
+where
+    T: Eq + Clone + Hash,
+{
+    window: LruQueue<T>,
+    probation: LruQueue<T>,
+    protected: LruQueue<T>,
+    sketch: CountMinSketch,
+}
+
+impl<T> WTinyLfu<T>
+where
+    T: Eq + Clone + Hash,
+{
+    // Share of the entries kept in the window, and of the main area kept
+    // protected, in percent
+    const WINDOW_PERCENT: usize = 1;
+    const PROTECTED_PERCENT: usize = 80;
+
+    pub fn new() -> Self {
+        Self {
+            window: LruQueue::new(),
+            probation: LruQueue::new(),
+            protected: LruQueue::new(),
+            sketch: CountMinSketch::new(),
+        }
+    }
+
+    fn len(self: &WTinyLfu<T>) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+
+    fn window_capacity(self: &WTinyLfu<T>) -> usize {
+        (self.len() * Self::WINDOW_PERCENT / 100).max(1)
+    }
+
+    fn protected_capacity(self: &WTinyLfu<T>) -> usize {
+        ((self.probation.len() + self.protected.len()) * Self::PROTECTED_PERCENT / 100).max(1)
+    }
+
+    // Move the oldest window entry into probation
+    fn admit(self: &mut WTinyLfu<T>) {
+        if let Some(key) = self.window.evict_lru() {
+            self.probation.add_lru(&key);
+        }
+    }
+
+    // Entry the main area gives up first
+    fn main_victim(self: &WTinyLfu<T>) -> Option<&T> {
+        self.probation.peek_lru().or_else(|| self.protected.peek_lru())
+    }
+
+    fn evict_main(self: &mut WTinyLfu<T>) -> Option<T> {
+        self.probation.evict_lru().or_else(|| self.protected.evict_lru())
+    }
+}
+
+impl<T> EvictionPolicy<T> for WTinyLfu<T>
+where
+    T: Eq + Clone + Hash,
+{
+    fn insert(&mut self, key: &T) {
+        self.remove(key);
+        self.window.add_lru(key);
+        self.sketch.ensure_capacity(self.len());
+        self.sketch.increment(key);
+    }
+
+    fn touch(&mut self, key: &T) {
+        self.sketch.increment(key);
+        if self.window.contains(key) {
+            self.window.add_lru(key);
+        } else if self.protected.contains(key) {
+            self.protected.add_lru(key);
+        } else if self.probation.evict_lru_by_value(key).is_some() {
+            self.protected.add_lru(key);
+            // Keep protected to its share, demoting its oldest back to probation
+            while self.protected.len() > self.protected_capacity() {
+                match self.protected.evict_lru() {
+                    Some(demoted) => self.probation.add_lru(&demoted),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &T) {
+        self.window.evict_lru_by_value(key);
+        self.probation.evict_lru_by_value(key);
+        self.protected.evict_lru_by_value(key);
+    }
+
+    fn evict(&mut self) -> Option<T> {
+        // Until something reached the main area there is nothing to compete
+        // with, so the window overflow goes in unchallenged
+        if self.main_victim().is_none() {
+            while self.window.len() > self.window_capacity() {
+                self.admit();
+            }
+        }
+
+        if self.window.len() <= self.window_capacity() {
+            return self.evict_main().or_else(|| self.window.evict_lru());
+        }
+
+        let candidate = self.window.peek_lru()?;
+        let victim = self.main_victim()?;
+        if self.sketch.estimate(candidate) > self.sketch.estimate(victim) {
+            let victim = self.evict_main();
+            self.admit();
+            victim
+        } else {
+            self.window.evict_lru()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eviction::tests::check_invariants;
+
+    #[test]
+    fn tiny_lfu_invariants() {
+        check_invariants(WTinyLfu::new());
+    }
+
+    #[test]
+    fn sketch_grows_with_entries() {
+        let mut tiny_lfu = WTinyLfu::new();
+        for key in 0..1000 {
+            tiny_lfu.insert(&key);
+        }
+        assert_eq!(tiny_lfu.sketch.width(), 1024);
+    }
+
+    #[test]
+    fn tiny_lfu_admits_frequent_candidates() {
+        let mut tiny_lfu = WTinyLfu::new();
+        for key in 0..10 {
+            tiny_lfu.insert(&key);
+        }
+        // The window overflow fills the main area, 0 its oldest entry
+        assert_eq!(tiny_lfu.evict(), Some(0));
+
+        tiny_lfu.insert(&100);
+        for _ in 0..3 {
+            tiny_lfu.touch(&100);
+        }
+        tiny_lfu.insert(&200);
+        // 9 is seen no more often than the main area's oldest, so it goes
+        assert_eq!(tiny_lfu.evict(), Some(9));
+        // 100 is seen more often, so the main area's oldest makes room for it
+        assert_eq!(tiny_lfu.evict(), Some(1));
+        assert!(tiny_lfu.probation.contains(&100));
+    }
+}
@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Byte relay between two connections, used once the proxy stops parsing HTTP
+// (CONNECT tunnels)
+pub struct Tunnel {
+    client: TcpStream,
+    upstream: TcpStream,
+    idle_timeout: Duration,
+}
+
+impl Tunnel {
+    const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+    pub fn new(client: TcpStream, upstream: TcpStream, idle_timeout: Duration) -> Self {
+        Self {
+            client,
+            upstream,
+            idle_timeout,
+        }
+    }
+
+    // Relay bytes in both directions until both sides close, or neither side
+    // sends anything for idle_timeout
+    pub fn relay(self: Tunnel) -> Result<(), Box<dyn Error>> {
+        self.client.set_read_timeout(Some(self.idle_timeout))?;
+        self.upstream.set_read_timeout(Some(self.idle_timeout))?;
+
+        // millis since start of the last byte relayed in either direction
+        let start = Instant::now();
+        let last_activity = Arc::new(AtomicU64::new(0));
+
+        let downstream = {
+            let from = self.upstream.try_clone()?;
+            let to = self.client.try_clone()?;
+            let last_activity = last_activity.clone();
+            let idle_timeout = self.idle_timeout;
+            thread::spawn(move || Self::copy(from, to, start, &last_activity, idle_timeout))
+        };
+        let upstream_result = Self::copy(
+            self.client.try_clone()?,
+            self.upstream.try_clone()?,
+            start,
+            &last_activity,
+            self.idle_timeout,
+        );
+        let downstream_result = downstream
+            .join()
+            .map_err(|_| "tunnel relay thread panicked")?;
+
+        // Either side may already be gone
+        let _ = self.client.shutdown(Shutdown::Both);
+        let _ = self.upstream.shutdown(Shutdown::Both);
+        upstream_result?;
+        downstream_result?;
+        Ok(())
+    }
+
+    // Copy one direction until EOF, passing the half close on, or until the
+    // whole tunnel has been idle too long
+    fn copy(
+        mut from: TcpStream,
+        mut to: TcpStream,
+        start: Instant,
+        last_activity: &AtomicU64,
+        idle_timeout: Duration,
+    ) -> Result<(), std::io::Error> {
+        let mut buffer = vec![0; Self::RELAY_BUFFER_SIZE];
+        loop {
+            match from.read(&mut buffer) {
+                Ok(0) => {
+                    let _ = to.shutdown(Shutdown::Write);
+                    return Ok(());
+                }
+                Ok(bytes_read) => {
+                    if let Err(e) = to.write_all(&buffer[..bytes_read]) {
+                        Self::close(&from, &to);
+                        return Err(e);
+                    }
+                    last_activity.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    // Only give up if the other direction was quiet as well.
+                    // It may have stored a later time since start was read.
+                    let idle_millis = (start.elapsed().as_millis() as u64)
+                        .saturating_sub(last_activity.load(Ordering::Relaxed));
+                    if idle_millis >= idle_timeout.as_millis() as u64 {
+                        Self::close(&from, &to);
+                        return Ok(());
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    Self::close(&from, &to);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Close both connections, which also wakes up the other direction
+    fn close(from: &TcpStream, to: &TcpStream) {
+        let _ = from.shutdown(Shutdown::Both);
+        let _ = to.shutdown(Shutdown::Both);
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// An upstream connection parked until the next request to the same origin
+struct IdleConnection {
+    stream: TcpStream,
+    since: Instant,
+}
+
+// Idle upstream connections kept open for reuse, keyed by host and port
+pub struct UpstreamPool {
+    idle: Mutex<HashMap<(String, u16), Vec<IdleConnection>>>,
+    // idle connections kept per host and port
+    max_idle: usize,
+    idle_timeout: Duration,
+    // how long opening a new connection may take
+    connect_timeout: Duration,
+}
+
+impl UpstreamPool {
+    pub fn new(max_idle: usize, idle_timeout: Duration, connect_timeout: Duration) -> Self {
+        Self {
+            idle: Mutex::new(HashMap::new()),
+            max_idle,
+            idle_timeout,
+            connect_timeout,
+        }
+    }
+
+    // Connection to host:port, reusing an idle one unless fresh is asked for.
+    // Also returns whether the connection was reused.
+    pub fn connect(
+        self: &UpstreamPool,
+        host: &str,
+        port: u16,
+        fresh: bool,
+    ) -> Result<(TcpStream, bool), Box<dyn Error>> {
+        if !fresh {
+            while let Some(idle) = self.take_idle(host, port)? {
+                if idle.since.elapsed() < self.idle_timeout && Self::is_alive(&idle.stream) {
+                    return Ok((idle.stream, true));
+                }
+            }
+        }
+
+        let stream = self.dial(host, port)?;
+        stream.set_nodelay(true)?;
+        Ok((stream, false))
+    }
+
+    // Open a new connection, trying each address host resolves to in turn
+    fn dial(self: &UpstreamPool, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for address in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_error = Some(err),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(ErrorKind::NotFound, format!("no address found for {}", host))
+        }))
+    }
+
+    // Park a connection whose last response was fully read, closing it instead
+    // if the host already has enough idle ones. A zero idle timeout or limit
+    // parks none.
+    pub fn release(self: &UpstreamPool, host: &str, port: u16, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+        if self.idle_timeout.is_zero() || self.max_idle == 0 {
+            return Ok(());
+        }
+        let mut idle = self.idle.lock().map_err(|_| "upstream pool lock poisoned")?;
+        // Close expired connections of every host, not only this one, so
+        // hosts no longer asked for don't hold them open
+        idle.retain(|_, connections| {
+            connections.retain(|connection| connection.since.elapsed() < self.idle_timeout);
+            !connections.is_empty()
+        });
+        let connections = idle.entry((host.to_string(), port)).or_default();
+        if connections.len() < self.max_idle {
+            connections.push(IdleConnection {
+                stream,
+                since: Instant::now(),
+            });
+        }
+        Ok(())
+    }
+
+    // Most recently parked connection for host:port
+    fn take_idle(self: &UpstreamPool, host: &str, port: u16) -> Result<Option<IdleConnection>, Box<dyn Error>> {
+        let mut idle = self.idle.lock().map_err(|_| "upstream pool lock poisoned")?;
+        let key = (host.to_string(), port);
+        let connection = idle.get_mut(&key).and_then(|connections| connections.pop());
+        if idle.get(&key).is_some_and(|connections| connections.is_empty()) {
+            idle.remove(&key);
+        }
+        Ok(connection)
+    }
+
+    // An idle connection is only usable if the server hasn't closed it and
+    // hasn't sent anything unasked for
+    fn is_alive(stream: &TcpStream) -> bool {
+        let peeked = stream.set_nonblocking(true).and_then(|()| stream.peek(&mut [0; 1]));
+        let is_alive = matches!(&peeked, Err(e) if e.kind() == ErrorKind::WouldBlock);
+        is_alive && stream.set_nonblocking(false).is_ok()
+    }
+}
+
@@ -0,0 +1,190 @@
+use std::error::Error;
+
+// Target of a request, resolved from the request-target and the Host header
+// following RFC 9112 section 3.2
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Url {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+    pub query: Option<String>,
+}
+
+impl Url {
+    pub const HTTP_SCHEME: &'static str = "http";
+    pub const HTTP_DEFAULT_PORT: u16 = 80;
+    const SCHEME_SEPARATOR: &'static str = "://";
+    const PATH_START: char = '/';
+    const QUERY_START: char = '?';
+    const FRAGMENT_START: char = '#';
+    const USERINFO_END: char = '@';
+    const PORT_SEPARATOR: char = ':';
+    const ASTERISK_FORM: &'static str = "*";
+
+    // Splits an authority (host[:port]) into a lowercase host and port. IPv6
+    // hosts are kept without their brackets.
+    pub fn parse_authority(
+        authority: &str,
+        default_port: Option<u16>,
+    ) -> Result<(String, u16), Box<dyn Error>> {
+        if authority.contains(Self::USERINFO_END) {
+            return Err(format!("userinfo not allowed in authority {}", authority).into());
+        }
+
+        let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+            // IPv6 literal
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| format!("unclosed IPv6 literal in authority {}", authority))?;
+            match rest.strip_prefix(Self::PORT_SEPARATOR) {
+                Some(port) => (host, Some(port)),
+                None if rest.is_empty() => (host, None),
+                None => return Err(format!("invalid authority {}", authority).into()),
+            }
+        } else {
+            match authority.split_once(Self::PORT_SEPARATOR) {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+
+        let is_valid_host = !host.is_empty()
+            && host.bytes().all(|b| {
+                b.is_ascii_alphanumeric() || b"-._~%!$&'()*+,;=:".contains(&b)
+            });
+        if !is_valid_host {
+            return Err(format!("invalid host in authority {}", authority).into());
+        }
+
+        // An empty port (host:) means the default one
+        let port = match port.filter(|port| !port.is_empty()) {
+            Some(port) => port
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port in authority {}", authority))?,
+            None => default_port.ok_or_else(|| format!("missing port in authority {}", authority))?,
+        };
+
+        Ok((host.to_lowercase(), port))
+    }
+
+    // Resolve the target of a request from its request-target, which is either
+    // in origin-form (/path?query, authority from Host) or absolute-form
+    // (http://host:port/path?query, which must agree with Host if given)
+    pub fn from_request(target: &str, host_header: Option<&String>) -> Result<Self, Box<dyn Error>> {
+        let host_authority = host_header
+            .map(|host| Self::parse_authority(host.trim(), Some(Self::HTTP_DEFAULT_PORT)))
+            .transpose()?;
+
+        if target.starts_with(Self::PATH_START) || target == Self::ASTERISK_FORM {
+            let (host, port) = host_authority.ok_or("Host header not found")?;
+            let (path, query) = Self::split_path_query(target);
+            return Ok(Url {
+                scheme: Self::HTTP_SCHEME.to_string(),
+                host,
+                port,
+                path,
+                query,
+            });
+        }
+
+        let (scheme, rest) = target
+            .split_once(Self::SCHEME_SEPARATOR)
+            .ok_or_else(|| format!("invalid request target {}", target))?;
+        if !scheme.eq_ignore_ascii_case(Self::HTTP_SCHEME) {
+            return Err(format!("unsupported scheme {}", scheme).into());
+        }
+
+        let authority_end = rest
+            .find([Self::PATH_START, Self::QUERY_START, Self::FRAGMENT_START])
+            .unwrap_or(rest.len());
+        let (host, port) =
+            Self::parse_authority(&rest[..authority_end], Some(Self::HTTP_DEFAULT_PORT))?;
+        if host_authority.is_some_and(|authority| authority != (host.clone(), port)) {
+            return Err(format!("Host header does not match request target {}", target).into());
+        }
+
+        let (path, query) = Self::split_path_query(&rest[authority_end..]);
+        Ok(Url {
+            scheme: scheme.to_lowercase(),
+            host,
+            port,
+            path,
+            query,
+        })
+    }
+
+    // Splits path and query, dropping any fragment. An empty path becomes /.
+    fn split_path_query(target: &str) -> (String, Option<String>) {
+        let target = target
+            .split(Self::FRAGMENT_START)
+            .next()
+            .unwrap_or_default();
+        let (path, query) = match target.split_once(Self::QUERY_START) {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+        let path = if path.is_empty() {
+            Self::PATH_START.to_string()
+        } else {
+            path.to_string()
+        };
+        (path, query)
+    }
+
+    // host:port to dial, with IPv6 hosts in brackets
+    pub fn authority(self: &Url) -> String {
+        format!("{}:{}", self.bracketed_host(), self.port)
+    }
+
+    // Value for the Host header, leaving out the default port
+    pub fn host_header(self: &Url) -> String {
+        if self.port == Self::HTTP_DEFAULT_PORT {
+            self.bracketed_host()
+        } else {
+            self.authority()
+        }
+    }
+
+    // Request-target to send to the origin server (/path?query)
+    pub fn origin_form(self: &Url) -> String {
+        match &self.query {
+            Some(query) => format!("{}{}{}", self.path, Self::QUERY_START, query),
+            None => self.path.clone(),
+        }
+    }
+
+    // Resolve a URI reference (as in Location) against this url. Only http
+    // references and paths are understood, and dot segments are left alone.
+    pub fn resolve(self: &Url, reference: &str) -> Option<Url> {
+        let reference = reference.trim();
+        if reference.contains(Self::SCHEME_SEPARATOR) {
+            return Self::from_request(reference, None).ok();
+        }
+
+        // A relative path replaces the last segment of this url's path
+        let target = if reference.starts_with(Self::PATH_START) {
+            reference.to_string()
+        } else {
+            let directory = match self.path.rfind(Self::PATH_START) {
+                Some(end) => &self.path[..=end],
+                None => "/",
+            };
+            format!("{}{}", directory, reference)
+        };
+        Self::from_request(&target, Some(&self.host_header())).ok()
+    }
+
+    // Whether both urls have the same scheme, host and port
+    pub fn same_origin(self: &Url, other: &Url) -> bool {
+        self.scheme == other.scheme && self.host == other.host && self.port == other.port
+    }
+
+    fn bracketed_host(self: &Url) -> String {
+        if self.host.contains(Self::PORT_SEPARATOR) {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        }
+    }
+}
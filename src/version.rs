@@ -0,0 +1,50 @@
+use crate::error::ProxyError;
+use std::fmt;
+
+// HTTP version of a message. Later 1.x minor versions are served as 1.1,
+// which they must be compatible with (RFC 9110 section 2.5).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    const PREFIX: &'static str = "HTTP/";
+
+    // Parses HTTP/<digit>.<digit>, errors are reported as the client's
+    pub fn parse(version: &str) -> Result<Self, ProxyError> {
+        let invalid = || ProxyError::BadRequest(format!("invalid HTTP version {}", version));
+        let number = version.strip_prefix(Self::PREFIX).ok_or_else(invalid)?.as_bytes();
+        let [major, b'.', minor] = number else {
+            return Err(invalid());
+        };
+        if !major.is_ascii_digit() || !minor.is_ascii_digit() {
+            return Err(invalid());
+        }
+
+        match (major, minor) {
+            (b'1', b'0') => Ok(Version::Http10),
+            (b'1', _) => Ok(Version::Http11),
+            _ => Err(ProxyError::BadRequest(format!("unsupported HTTP version {}", version))),
+        }
+    }
+
+    pub fn as_str(self: &Version) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+
+    // The version number alone, as used in Via
+    pub fn number(self: &Version) -> &'static str {
+        &self.as_str()[Self::PREFIX.len()..]
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}